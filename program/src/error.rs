@@ -18,6 +18,62 @@ pub enum EngineError {
     MarketInactive,
     #[error("Unauthorized operation")]
     Unauthorized,
+    #[error("Account discriminator does not match the expected type")]
+    InvalidAccountType,
+    #[error("Account layout version is not supported")]
+    InvalidAccountVersion,
+    #[error("Account address does not match the expected program address")]
+    InvalidAccountAddress,
+    #[error("Account is already initialized")]
+    AlreadyInitialized,
+    #[error("User account still has open positions, orders or events")]
+    AccountNotEmpty,
+    #[error("Requested open-order capacity is invalid")]
+    InvalidOrderCapacity,
+    #[error("No free open-order slot available")]
+    OrderSlotsFull,
+    #[error("Market parameters are invalid")]
+    InvalidMarketParams,
+    #[error("Market status transition is not allowed")]
+    InvalidStatusTransition,
+    #[error("Order price or size is invalid for this market")]
+    InvalidOrderParams,
+    #[error("Amount is not a whole number of lots")]
+    InvalidLotAmount,
+    #[error("Order price is outside the oracle price band")]
+    PriceOutsideBand,
+    #[error("Order not found")]
+    OrderNotFound,
+    #[error("Too many orders in a single instruction")]
+    TooManyOrders,
+    #[error("Order expiry is in the past")]
+    OrderExpired,
+    #[error("Reduce-only order would increase or flip the position")]
+    OrderNotReducing,
+    #[error("Trigger condition is not met")]
+    TriggerNotMet,
+    #[error("TWAP order is no longer active")]
+    TwapNotActive,
+    #[error("Next TWAP slice is not due yet")]
+    TwapSliceNotDue,
+    #[error("Auction window has not ended yet")]
+    AuctionNotDue,
+    #[error("Market is not in the required matching mode")]
+    InvalidMatchingMode,
+    #[error("Quote request is no longer open")]
+    QuoteRequestClosed,
+    #[error("Quote has expired")]
+    QuoteExpired,
+    #[error("Accounts have no opposite unrealized PnL to settle")]
+    NoSettleablePnl,
+    #[error("Withdrawal exceeds deposits and matured settled PnL")]
+    WithdrawLimitExceeded,
+    #[error("Operation is not supported for this market kind")]
+    InvalidMarketKind,
+    #[error("Free balance does not cover the order")]
+    InsufficientFunds,
+    #[error("Order neither filled nor rested")]
+    OrderNotExecuted,
 }
 
 impl From<EngineError> for ProgramError {
@@ -1,6 +1,11 @@
-use solana_program::pubkey::Pubkey;
+use solana_program::{pubkey, pubkey::Pubkey};
 
 /// Program id for the on-chain price oracle this matching engine expects.
 pub fn oracle_program_id() -> Pubkey {
     Pubkey::new_from_array([2u8; 32])
 }
+
+/// Program id of the SPL Token program holding spot market vaults.
+pub fn token_program_id() -> Pubkey {
+    pubkey!("TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA")
+}
@@ -1,16 +1,31 @@
 use crate::error::EngineError;
+use crate::state::{
+    Market, MarketKind, MarketStatus, MatchingMode, OrderType, Side, TriggerDirection, UserAccount,
+};
 use borsh::{BorshDeserialize, BorshSerialize};
-use solana_program::program_error::ProgramError;
+use solana_program::{clock::UnixTimestamp, program_error::ProgramError, pubkey::Pubkey};
 
 /// Instructions supported by the matching engine program.
 #[derive(Clone, Debug, BorshSerialize, BorshDeserialize, PartialEq)]
 pub enum EngineInstruction {
+    /// Accounts: market (writable), admin (signer), oracle, event queue.
+    /// Spot markets also take the base mint, quote mint, base vault and
+    /// quote vault, with both vaults owned by the PDA `[b"vault", market]`.
     InitializeMarket {
         fee_bps: u16,
+        base_lot_size: u64,
+        quote_lot_size: u64,
+        tick_size: i64,
+        min_base_order_size: i64,
+        kind: MarketKind,
     },
     Deposit {
         amount: u64,
     },
+    /// Withdraws matured settled PnL first, then deposits. The account
+    /// must stay initially margined afterwards.
+    ///
+    /// Accounts: market, user (writable), owner (signer), oracle.
     Withdraw {
         amount: u64,
     },
@@ -18,6 +33,11 @@ pub enum EngineInstruction {
         price_lots: i64,
         max_base_lots: i64,
         side_is_bid: bool,
+        order_type: OrderType,
+        expiry_timestamp: Option<UnixTimestamp>,
+        reduce_only: bool,
+        peg: Option<PegParams>,
+        display_base_lots: Option<i64>,
     },
     CancelOrder {
         order_id: u128,
@@ -29,6 +49,341 @@ pub enum EngineInstruction {
     Liquidate {
         max_liq_amount: u64,
     },
+    /// Creates a user account at the PDA derived from
+    /// `[market, owner, account_num]` with `max_orders` open-order slots,
+    /// funded by the owner.
+    ///
+    /// Accounts: market, user (writable), owner (signer, writable), system program.
+    CreateUserAccount {
+        account_num: u32,
+        max_orders: u16,
+    },
+    /// Closes an empty user account and returns its lamports to the owner.
+    ///
+    /// Accounts: market, user (writable), owner (signer, writable), event queue.
+    CloseUserAccount,
+    /// Pops up to `limit` processed events from the head of the event queue.
+    ///
+    /// Accounts: market, admin (signer), event queue (writable).
+    ConsumeEvents {
+        limit: u16,
+    },
+    /// Grows a user account to hold `max_orders` open-order slots. The
+    /// owner pays for the additional rent.
+    ///
+    /// Accounts: market, user (writable), owner (signer, writable), system program.
+    ResizeUserAccount {
+        max_orders: u16,
+    },
+    /// Sets the key allowed to place and cancel orders on behalf of the
+    /// owner. `Pubkey::default()` clears the delegate.
+    ///
+    /// Accounts: market, user (writable), owner (signer).
+    SetDelegate {
+        delegate: Pubkey,
+    },
+    /// Updates the given market parameters. Fields left as `None` are
+    /// unchanged.
+    ///
+    /// Accounts: market (writable), admin (signer), event queue (writable).
+    UpdateMarketParams {
+        params: MarketParamsUpdate,
+    },
+    /// Nominates `new_admin` to take over the market. Takes effect once the
+    /// nominee calls `AcceptAdmin`; `Pubkey::default()` clears the proposal.
+    ///
+    /// Accounts: market (writable), admin (signer).
+    ProposeAdmin {
+        new_admin: Pubkey,
+    },
+    /// Completes an admin transfer started with `ProposeAdmin`.
+    ///
+    /// Accounts: market (writable), pending admin (signer).
+    AcceptAdmin,
+    /// Moves the market to a new lifecycle status.
+    ///
+    /// Accounts: market (writable), admin (signer).
+    SetMarketStatus {
+        status: MarketStatus,
+    },
+    /// Cancels up to `limit` resting orders, optionally only on one side,
+    /// emitting an `Out` event for each.
+    ///
+    /// Accounts: market, user (writable), owner or delegate (signer),
+    /// event queue (writable).
+    CancelAllOrders {
+        side: Option<Side>,
+        limit: u8,
+    },
+    /// Atomically amends a resting order. Reducing only the size keeps the
+    /// order's queue priority; any other change cancels it and places a new
+    /// order. Pegged orders keep their peg and ignore `new_price_lots`.
+    /// Uses the same accounts as `PlaceOrder`.
+    ReplaceOrder {
+        order_id: u128,
+        new_price_lots: i64,
+        new_base_lots: i64,
+    },
+    /// Places up to [MAX_BATCH_ORDERS] orders against a single load of the
+    /// market and user accounts, optionally cancelling every resting order
+    /// first. The orders times the makers' open-order slots may not exceed
+    /// [MAX_BATCH_MAKER_SLOTS]. Uses the same accounts as `PlaceOrder`.
+    PlaceOrders {
+        orders: Vec<OrderParams>,
+        cancel_existing: bool,
+    },
+    /// Permissionless crank removing up to `limit` expired orders from the
+    /// given user accounts, emitting an `Out` event for each.
+    ///
+    /// Accounts: market, event queue (writable), user accounts (writable).
+    PruneExpiredOrders {
+        limit: u8,
+    },
+    /// Stores an order that is submitted once the oracle price crosses
+    /// `trigger_price` in `direction`. The trigger store is created on first
+    /// use, paid for by the authority.
+    ///
+    /// Accounts: market, user, owner or delegate (signer, writable),
+    /// trigger orders (writable), system program.
+    PlaceTriggerOrder {
+        trigger_price: i64,
+        direction: TriggerDirection,
+        params: OrderParams,
+    },
+    /// Removes a pending trigger order.
+    ///
+    /// Accounts: market, user, owner or delegate (signer), trigger orders (writable).
+    CancelTriggerOrder {
+        trigger_id: u64,
+    },
+    /// Permissionless crank submitting a trigger order whose condition holds
+    /// at the current oracle price. The keeper's user account is paid the
+    /// market's keeper fee by the order owner. Fails, leaving the trigger in
+    /// place, if the order neither fills nor rests.
+    ///
+    /// Accounts: market (writable), user (writable), trigger orders (writable),
+    /// keeper user account (writable), event queue (writable), oracle,
+    /// followed by maker user accounts (writable).
+    ExecuteTriggerOrder {
+        trigger_id: u64,
+    },
+    /// Creates a TWAP order at the PDA derived from `[b"twap", user, nonce]`,
+    /// funded by the authority.
+    ///
+    /// Accounts: market, user, owner or delegate (signer, writable),
+    /// TWAP order (writable), system program.
+    PlaceTwapOrder {
+        nonce: u64,
+        params: TwapParams,
+    },
+    /// Stops a TWAP order from executing further slices.
+    ///
+    /// Accounts: market, user, owner or delegate (signer), TWAP order (writable).
+    CancelTwapOrder,
+    /// Permissionless crank executing the next due slice of a TWAP order as
+    /// an IOC order at the TWAP's limit price. The crank picks the makers,
+    /// so a slice that fills nothing fails instead of being used up.
+    ///
+    /// Accounts: market (writable), user (writable), TWAP order (writable),
+    /// event queue (writable), oracle, followed by maker user accounts
+    /// (writable).
+    ExecuteTwapSlice,
+    /// Clears the resting orders of the given user accounts at a single
+    /// uniform price once the auction window has ended, then opens the next
+    /// window. Only active batch auction markets. The caller decides which
+    /// orders take part, so only the admin may run it.
+    ///
+    /// Accounts: market (writable), admin (signer), event queue (writable),
+    /// oracle, followed by user accounts (writable).
+    ClearAuction,
+    /// Runs the opening auction of a pre-open market: crossing resting
+    /// orders of the given user accounts are uncrossed at the equilibrium
+    /// price and the market becomes active.
+    ///
+    /// Accounts: market (writable), admin (signer), event queue (writable),
+    /// oracle, followed by user accounts (writable).
+    OpenMarket,
+    /// Posts a request for quotes on a block trade outside the book, at the
+    /// PDA derived from `[b"rfq", user, nonce]`, funded by the authority.
+    ///
+    /// Accounts: market, user, owner or delegate (signer, writable),
+    /// quote request (writable), system program.
+    CreateQuoteRequest {
+        nonce: u64,
+        side_is_bid: bool,
+        base_lots: i64,
+        expiry_ts: UnixTimestamp,
+    },
+    /// Posts or updates a maker's quote for an open request, at the PDA
+    /// derived from `[b"quote", request, maker]`, funded by the authority.
+    ///
+    /// Accounts: market, quote request, maker user, maker owner or delegate
+    /// (signer, writable), quote (writable), system program.
+    PostQuote {
+        price_lots: i64,
+        expiry_ts: UnixTimestamp,
+    },
+    /// Fills the whole request against a live quote, settling both user
+    /// accounts at the quoted price. Both sides are held to the market
+    /// status and perp margin rules of book orders.
+    ///
+    /// Accounts: market, user (writable), owner or delegate (signer),
+    /// quote request (writable), quote, maker user (writable),
+    /// event queue (writable), oracle.
+    AcceptQuote,
+    /// Withdraws an open quote request, or clears a filled one, closing it
+    /// and returning its rent to the owner. Makers close their quotes with
+    /// `CancelQuote`.
+    ///
+    /// Accounts: market, user, owner or delegate (signer), quote request
+    /// (writable), owner (writable).
+    CancelQuoteRequest,
+    /// Permissionless crank settling the opposite unrealized PnL of two
+    /// user accounts at the oracle price. The winner's share becomes
+    /// withdrawable once the market's PnL maturity delay has passed.
+    ///
+    /// Accounts: market, user (writable), user (writable), oracle.
+    SettlePnl,
+    /// Moves base and quote tokens from the owner's token accounts into a
+    /// spot market's vaults and credits the user account.
+    ///
+    /// Accounts: market, user (writable), owner (signer), owner base token
+    /// account (writable), owner quote token account (writable), base vault
+    /// (writable), quote vault (writable), token program.
+    DepositFunds {
+        base_amount: u64,
+        quote_amount: u64,
+    },
+    /// Pays out all free (unlocked) base and quote balances of a spot user
+    /// account to the owner's token accounts.
+    ///
+    /// Accounts: market, user (writable), owner (signer), owner base token
+    /// account (writable), owner quote token account (writable), base vault
+    /// (writable), quote vault (writable), vault authority, token program.
+    SettleFunds,
+    /// Withdraws a maker's quote, closing it and returning its rent to the
+    /// maker's owner. Works whether or not the request is still open.
+    ///
+    /// Accounts: market, maker user, maker owner or delegate (signer),
+    /// quote (writable), maker owner (writable).
+    CancelQuote,
+    /// Closes a TWAP order account, stopping it if still active, and
+    /// returns its rent to the owner.
+    ///
+    /// Accounts: market, user, owner or delegate (signer), TWAP order
+    /// (writable), owner (writable).
+    CloseTwapOrder,
+}
+
+/// Maximum number of orders accepted by `PlaceOrders`.
+pub const MAX_BATCH_ORDERS: usize = 16;
+
+/// Maximum number of maker open-order slots a `PlaceOrders` batch may scan.
+/// Each order rebuilds its match candidates from every slot of every maker
+/// passed in, so the batch is bounded by orders times maker slots rather
+/// than by order count alone: a full batch against two maximum-sized
+/// maker accounts, or a handful of orders against many makers.
+pub const MAX_BATCH_MAKER_SLOTS: usize = MAX_BATCH_ORDERS * 2 * UserAccount::MAX_OPEN_ORDERS;
+
+/// Parameters of a single order submitted through the placement path.
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize, PartialEq)]
+pub struct OrderParams {
+    pub price_lots: i64,
+    pub max_base_lots: i64,
+    pub side_is_bid: bool,
+    pub order_type: OrderType,
+    pub expiry_timestamp: Option<UnixTimestamp>,
+    pub reduce_only: bool,
+    /// Pegs the order to the oracle; `price_lots` is ignored when set.
+    pub peg: Option<PegParams>,
+    /// Makes a limit order an iceberg showing at most this many base lots,
+    /// refilled from the hidden remainder after each full fill.
+    pub display_base_lots: Option<i64>,
+}
+
+impl OrderParams {
+    /// Upper bound on the serialized size of order parameters.
+    pub const MAX_LEN: usize = 8 + 8 + 1 + 1 + 9 + 1 + 1 + PegParams::LEN + 9;
+}
+
+/// Oracle peg of an order whose price is `oracle_price + offset_lots`,
+/// never worse for the owner than `limit_lots` when given.
+#[derive(Copy, Clone, Debug, BorshSerialize, BorshDeserialize, PartialEq)]
+pub struct PegParams {
+    pub offset_lots: i64,
+    pub limit_lots: Option<i64>,
+}
+
+impl PegParams {
+    /// Serialized size of the peg parameters.
+    pub const LEN: usize = 8 + 9;
+}
+
+/// Parameters of a TWAP order split into `num_slices` IOC child orders,
+/// one every `interval_secs`, none trading beyond `limit_price_lots`.
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize, PartialEq)]
+pub struct TwapParams {
+    pub side_is_bid: bool,
+    pub limit_price_lots: i64,
+    pub total_base_lots: i64,
+    pub num_slices: u16,
+    pub interval_secs: i64,
+}
+
+/// Optional market parameter overrides used by
+/// [EngineInstruction::UpdateMarketParams].
+#[derive(Clone, Debug, Default, BorshSerialize, BorshDeserialize, PartialEq)]
+pub struct MarketParamsUpdate {
+    pub fee_bps: Option<u16>,
+    pub oracle: Option<Pubkey>,
+    pub init_margin_bps: Option<u16>,
+    pub maint_margin_bps: Option<u16>,
+    pub tick_size: Option<i64>,
+    pub min_base_order_size: Option<i64>,
+    pub max_price_deviation_bps: Option<u16>,
+    pub keeper_fee_lots: Option<i64>,
+    pub matching_mode: Option<MatchingMode>,
+    pub auction_interval_secs: Option<i64>,
+    pub pnl_maturity_secs: Option<i64>,
+}
+
+impl MarketParamsUpdate {
+    /// Overwrites the fields of `market` that are set in this update.
+    pub fn apply(&self, market: &mut Market) {
+        if let Some(fee_bps) = self.fee_bps {
+            market.fee_bps = fee_bps;
+        }
+        if let Some(oracle) = self.oracle {
+            market.oracle = oracle;
+        }
+        if let Some(init_margin_bps) = self.init_margin_bps {
+            market.init_margin_bps = init_margin_bps;
+        }
+        if let Some(maint_margin_bps) = self.maint_margin_bps {
+            market.maint_margin_bps = maint_margin_bps;
+        }
+        if let Some(tick_size) = self.tick_size {
+            market.tick_size = tick_size;
+        }
+        if let Some(min_base_order_size) = self.min_base_order_size {
+            market.min_base_order_size = min_base_order_size;
+        }
+        if let Some(max_price_deviation_bps) = self.max_price_deviation_bps {
+            market.max_price_deviation_bps = max_price_deviation_bps;
+        }
+        if let Some(keeper_fee_lots) = self.keeper_fee_lots {
+            market.keeper_fee_lots = keeper_fee_lots;
+        }
+        if let Some(matching_mode) = self.matching_mode {
+            market.matching_mode = matching_mode;
+        }
+        if let Some(auction_interval_secs) = self.auction_interval_secs {
+            market.auction_interval_secs = auction_interval_secs;
+        }
+        if let Some(pnl_maturity_secs) = self.pnl_maturity_secs {
+            market.pnl_maturity_secs = pnl_maturity_secs;
+        }
+    }
 }
 
 impl EngineInstruction {
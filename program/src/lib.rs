@@ -4,9 +4,11 @@ pub mod ids;
 pub mod instruction;
 pub mod matching;
 pub mod oracle;
+pub mod pnl;
 pub mod processor;
 pub mod queue;
 pub mod state;
+pub mod token;
 pub mod utils;
 
 use solana_program::pubkey::Pubkey;
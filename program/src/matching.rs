@@ -1,61 +1,447 @@
-use crate::state::{Event, Order, UserAccount};
-use solana_program::pubkey::Pubkey;
+use crate::error::EngineError;
+use crate::state::{Event, Order, Side, UserAccount};
+use solana_program::{clock::UnixTimestamp, pubkey::Pubkey};
 
 /// Simple in-memory matching routine between an incoming taker order
-/// and an array of maker orders on the opposite side.
+/// and an array of maker orders on the opposite side. Only maker orders
+/// priced at or better than `limit_price_lots` are filled, best price
+/// first and, within a price level, in order id (time) priority. Pegged
+/// maker orders are priced off `oracle_price` at match time. Iceberg maker
+/// orders refilled from their reserve are requeued behind their price level
+/// under a fresh id taken from `seq_num`. Maker orders that have expired at
+/// `now` are removed instead of filled. Returns the unfilled base lots.
+#[allow(clippy::too_many_arguments)]
 pub fn match_orders(
     taker: &mut UserAccount,
     makers: &mut [UserAccount],
+    limit_price_lots: i64,
     mut remaining_base_lots: i64,
     max_quote_change: &mut i64,
     side_is_bid: bool,
+    oracle_price: i64,
+    seq_num: &mut u64,
+    now: UnixTimestamp,
     events: &mut Vec<Event>,
-) {
-    for maker in makers.iter_mut() {
+) -> i64 {
+    let mut candidates = Vec::new();
+    for (maker_idx, maker) in makers.iter_mut().enumerate() {
+        let (owner, account_num) = (maker.owner, maker.account_num);
+        for (slot, order) in maker.open_orders.iter_mut().enumerate() {
+            if !order.is_active || order.side_is_bid == side_is_bid {
+                continue;
+            }
+            if order.is_expired(now) {
+                remove_order(owner, account_num, order, events);
+                continue;
+            }
+
+            let Some(price_lots) = order.effective_price(oracle_price) else {
+                continue;
+            };
+            let crosses = if side_is_bid {
+                price_lots <= limit_price_lots
+            } else {
+                price_lots >= limit_price_lots
+            };
+            if crosses {
+                candidates.push((maker_idx, slot, price_lots));
+            }
+        }
+    }
+    let priority = |price_lots: i64, id: u128| {
+        let price_rank = if side_is_bid { price_lots } else { -price_lots };
+        (price_rank, id)
+    };
+    candidates.sort_by_key(|&(maker_idx, slot, price_lots)| {
+        priority(price_lots, makers[maker_idx].open_orders[slot].id)
+    });
+
+    let mut next = 0;
+    while next < candidates.len() {
         if remaining_base_lots <= 0 {
             break;
         }
+        let (maker_idx, slot, price_lots) = candidates[next];
+        next += 1;
 
-        for order in maker.open_orders.iter_mut() {
-            if !order.is_active || order.side_is_bid == side_is_bid {
+        let maker = &mut makers[maker_idx];
+        let order = &mut maker.open_orders[slot];
+
+        let mut trade_base = remaining_base_lots.min(order.base_lots);
+        if order.reduce_only {
+            let reducible = if order.side_is_bid {
+                -maker.base_position
+            } else {
+                maker.base_position
+            };
+            if reducible <= 0 {
+                remove_order(maker.owner, maker.account_num, order, events);
                 continue;
             }
+            trade_base = trade_base.min(reducible);
+        }
+        if trade_base <= 0 {
+            continue;
+        }
+
+        let quote_change = settle_trade(taker, maker, side_is_bid, price_lots, trade_base, events);
+        *max_quote_change += quote_change.abs();
+        remaining_base_lots -= trade_base;
+
+        let order = &mut maker.open_orders[slot];
+        order.base_lots -= trade_base;
+        if order.base_lots > 0
+            || !refill_order(maker.owner, maker.account_num, order, seq_num, events)
+        {
+            continue;
+        }
+
+        let key = priority(price_lots, order.id);
+        let pos = next
+            + candidates[next..].partition_point(|&(maker_idx, slot, price_lots)| {
+                priority(price_lots, makers[maker_idx].open_orders[slot].id) <= key
+            });
+        candidates.insert(pos, (maker_idx, slot, price_lots));
+    }
+
+    remaining_base_lots
+}
 
-            let trade_base = remaining_base_lots.min(order.base_lots);
-            if trade_base <= 0 {
+/// Settles a fill of `base_lots` at `price_lots` between `taker` and
+/// `maker`, the taker buying when `taker_is_bid`, and records the trade.
+/// Shared by book matching, auctions and RFQ block trades. Returns the
+/// quote lots exchanged.
+pub fn settle_trade(
+    taker: &mut UserAccount,
+    maker: &mut UserAccount,
+    taker_is_bid: bool,
+    price_lots: i64,
+    base_lots: i64,
+    events: &mut Vec<Event>,
+) -> i64 {
+    let quote_change = base_lots * price_lots;
+
+    if taker_is_bid {
+        taker.base_position += base_lots;
+        taker.quote_position -= quote_change;
+        maker.base_position -= base_lots;
+        maker.quote_position += quote_change;
+    } else {
+        taker.base_position -= base_lots;
+        taker.quote_position += quote_change;
+        maker.base_position += base_lots;
+        maker.quote_position -= quote_change;
+    }
+
+    events.push(Event::Trade {
+        maker: maker.owner,
+        maker_account_num: maker.account_num,
+        taker: taker.owner,
+        taker_account_num: taker.account_num,
+        price_lots,
+        base_lots,
+    });
+    quote_change
+}
+
+/// Handles an order whose visible size was just filled: an iceberg with
+/// reserve left is refilled under a fresh id from `seq_num` and a `Refill`
+/// event is recorded, any other order is deactivated. Returns whether the
+/// order was refilled.
+fn refill_order(
+    owner: Pubkey,
+    account_num: u32,
+    order: &mut Order,
+    seq_num: &mut u64,
+    events: &mut Vec<Event>,
+) -> bool {
+    if order.hidden_base_lots == 0 {
+        order.is_active = false;
+        return false;
+    }
+
+    let refill = order.display_base_lots.min(order.hidden_base_lots);
+    let old_order_id = order.id;
+    order.hidden_base_lots -= refill;
+    order.base_lots = refill;
+    order.id = *seq_num as u128;
+    *seq_num += 1;
+    events.push(Event::Refill {
+        owner,
+        account_num,
+        old_order_id,
+        order_id: order.id,
+        side_is_bid: order.side_is_bid,
+        base_lots: refill,
+    });
+    true
+}
+
+/// Finds the uniform price at which the `(price_lots, base_lots)` bids and
+/// asks match the most volume. Ties are broken by the smallest imbalance
+/// between demand and supply, then by distance to `reference_price`, then
+/// by the lower price. Returns the price and matched volume, or `None` if
+/// nothing crosses.
+pub fn auction_clearing_price(
+    bids: &[(i64, i64)],
+    asks: &[(i64, i64)],
+    reference_price: i64,
+) -> Option<(i64, i64)> {
+    let mut best: Option<(i64, i64, i64)> = None;
+    for &(price, _) in bids.iter().chain(asks.iter()) {
+        let demand: i64 = bids.iter().filter(|b| b.0 >= price).map(|b| b.1).sum();
+        let supply: i64 = asks.iter().filter(|a| a.0 <= price).map(|a| a.1).sum();
+        let volume = demand.min(supply);
+        if volume == 0 {
+            continue;
+        }
+        let imbalance = (demand - supply).abs();
+
+        let better = match best {
+            None => true,
+            Some((best_price, best_volume, best_imbalance)) => {
+                let distance = (price - reference_price).abs();
+                let best_distance = (best_price - reference_price).abs();
+                (volume, -imbalance, -distance, -price)
+                    > (best_volume, -best_imbalance, -best_distance, -best_price)
+            }
+        };
+        if better {
+            best = Some((price, volume, imbalance));
+        }
+    }
+    best.map(|(price, volume, _)| (price, volume))
+}
+
+/// Uncrosses the resting orders of `users` at a single clearing price.
+/// Bids at or above and asks at or below the price fill in price-time
+/// priority until the clearing volume is exhausted, all at the clearing
+/// price. Pegged orders are priced off `oracle_price`, expired orders are
+/// removed and reduce-only orders are capped by their owner's position.
+/// Returns the clearing price and volume, if anything crossed.
+pub fn clear_auction(
+    users: &mut [UserAccount],
+    oracle_price: i64,
+    seq_num: &mut u64,
+    now: UnixTimestamp,
+    events: &mut Vec<Event>,
+) -> Option<(i64, i64)> {
+    // (user index, slot, price, matchable size, id)
+    let mut bids = Vec::new();
+    let mut asks = Vec::new();
+    for (user_idx, user) in users.iter_mut().enumerate() {
+        let (owner, account_num) = (user.owner, user.account_num);
+        let mut reducible_by_bids = (-user.base_position).max(0);
+        let mut reducible_by_asks = user.base_position.max(0);
+        for (slot, order) in user.open_orders.iter_mut().enumerate() {
+            if !order.is_active {
+                continue;
+            }
+            if order.is_expired(now) {
+                remove_order(owner, account_num, order, events);
                 continue;
             }
+            let Some(price_lots) = order.effective_price(oracle_price) else {
+                continue;
+            };
 
-            let quote_change = trade_base * order.price_lots;
+            let mut base_lots = order.base_lots;
+            if order.reduce_only {
+                let reducible = if order.side_is_bid {
+                    &mut reducible_by_bids
+                } else {
+                    &mut reducible_by_asks
+                };
+                base_lots = base_lots.min(*reducible);
+                *reducible -= base_lots;
+            }
+            if base_lots <= 0 {
+                continue;
+            }
 
-            if side_is_bid {
-                taker.base_position += trade_base;
-                taker.quote_position -= quote_change;
-                maker.base_position -= trade_base;
-                maker.quote_position += quote_change;
+            let entry = (user_idx, slot, price_lots, base_lots, order.id);
+            if order.side_is_bid {
+                bids.push(entry);
             } else {
-                taker.base_position -= trade_base;
-                taker.quote_position += quote_change;
-                maker.base_position += trade_base;
-                maker.quote_position -= quote_change;
+                asks.push(entry);
             }
+        }
+    }
 
-            order.base_lots -= trade_base;
-            if order.base_lots == 0 {
-                order.is_active = false;
-            }
+    let sizes = |orders: &[(usize, usize, i64, i64, u128)]| -> Vec<(i64, i64)> {
+        orders.iter().map(|o| (o.2, o.3)).collect()
+    };
+    let (price_lots, volume) = auction_clearing_price(&sizes(&bids), &sizes(&asks), oracle_price)?;
+
+    bids.retain(|o| o.2 >= price_lots);
+    asks.retain(|o| o.2 <= price_lots);
+    bids.sort_by_key(|o| (-o.2, o.4));
+    asks.sort_by_key(|o| (o.2, o.4));
 
-            *max_quote_change += quote_change.abs();
-            remaining_base_lots -= trade_base;
+    let (mut bid_idx, mut ask_idx) = (0, 0);
+    let mut remaining = volume;
+    while remaining > 0 && bid_idx < bids.len() && ask_idx < asks.len() {
+        let bid = &mut bids[bid_idx];
+        let ask = &mut asks[ask_idx];
+        let trade_base = remaining.min(bid.3).min(ask.3);
 
+        if bid.0 == ask.0 {
+            // A self-cross leaves the position unchanged.
+            let user = &users[bid.0];
             events.push(Event::Trade {
-                maker: maker.owner,
-                taker: taker.owner,
-                price_lots: order.price_lots,
+                maker: user.owner,
+                maker_account_num: user.account_num,
+                taker: user.owner,
+                taker_account_num: user.account_num,
+                price_lots,
                 base_lots: trade_base,
             });
+        } else {
+            let (buyer, seller) = pair_mut(users, bid.0, ask.0);
+            // The earlier of the two orders is reported as the maker.
+            if bid.4 < ask.4 {
+                settle_trade(seller, buyer, false, price_lots, trade_base, events);
+            } else {
+                settle_trade(buyer, seller, true, price_lots, trade_base, events);
+            }
+        }
+
+        for entry in [&mut *bid, &mut *ask] {
+            let user = &mut users[entry.0];
+            let (owner, account_num) = (user.owner, user.account_num);
+            let order = &mut user.open_orders[entry.1];
+            order.base_lots -= trade_base;
+            if order.base_lots == 0 {
+                refill_order(owner, account_num, order, seq_num, events);
+            }
+            entry.3 -= trade_base;
+        }
+
+        remaining -= trade_base;
+        if bids[bid_idx].3 == 0 {
+            bid_idx += 1;
+        }
+        if asks[ask_idx].3 == 0 {
+            ask_idx += 1;
+        }
+    }
+
+    Some((price_lots, volume))
+}
+
+/// Returns mutable references to two distinct elements of `items`.
+fn pair_mut<T>(items: &mut [T], a: usize, b: usize) -> (&mut T, &mut T) {
+    if a < b {
+        let (left, right) = items.split_at_mut(b);
+        (&mut left[a], &mut right[0])
+    } else {
+        let (left, right) = items.split_at_mut(a);
+        (&mut right[0], &mut left[b])
+    }
+}
+
+/// Deactivates `order` and records an `Out` event for its remaining size.
+fn remove_order(owner: Pubkey, account_num: u32, order: &mut Order, events: &mut Vec<Event>) {
+    events.push(Event::Out {
+        owner,
+        account_num,
+        order_id: order.id,
+        side_is_bid: order.side_is_bid,
+        base_lots: order.total_base_lots(),
+    });
+    order.is_active = false;
+    order.base_lots = 0;
+    order.hidden_base_lots = 0;
+}
+
+/// Removes up to `limit` orders of `user` that have expired at `now`,
+/// recording an `Out` event for each. Returns the number removed.
+pub fn prune_expired_orders(
+    user: &mut UserAccount,
+    now: UnixTimestamp,
+    limit: usize,
+    events: &mut Vec<Event>,
+) -> usize {
+    let (owner, account_num) = (user.owner, user.account_num);
+    let mut pruned = 0;
+    for order in user.open_orders.iter_mut() {
+        if pruned >= limit {
+            break;
+        }
+        if order.is_active && order.is_expired(now) {
+            remove_order(owner, account_num, order, events);
+            pruned += 1;
+        }
+    }
+    pruned
+}
+
+/// Cancels up to `limit` active orders of `user`, restricted to `side` when
+/// given, and records an `Out` event for each. Returns the number of
+/// orders cancelled.
+pub fn cancel_orders(
+    user: &mut UserAccount,
+    side: Option<Side>,
+    limit: usize,
+    events: &mut Vec<Event>,
+) -> usize {
+    let mut cancelled = 0;
+    for order in user.open_orders.iter_mut() {
+        if cancelled >= limit {
+            break;
+        }
+        if !order.is_active || side.is_some_and(|s| s.is_bid() != order.side_is_bid) {
+            continue;
         }
+
+        remove_order(user.owner, user.account_num, order, events);
+        cancelled += 1;
     }
+    cancelled
+}
+
+/// Returns how many base lots a new reduce-only order on the given side may
+/// still take out of `user`'s position, after the size already committed
+/// to resting reduce-only orders on that side.
+pub fn reduce_only_capacity(user: &UserAccount, side_is_bid: bool) -> i64 {
+    position_capacity(user, side_is_bid, |o| o.reduce_only)
+}
+
+/// Like [reduce_only_capacity], but after every order resting on that side,
+/// so that no combination of fills can flip the position. Used while the
+/// market only accepts position-reducing orders.
+pub fn reducing_capacity(user: &UserAccount, side_is_bid: bool) -> i64 {
+    position_capacity(user, side_is_bid, |_| true)
+}
+
+fn position_capacity(
+    user: &UserAccount,
+    side_is_bid: bool,
+    counts: impl Fn(&Order) -> bool,
+) -> i64 {
+    let reducible = if side_is_bid {
+        -user.base_position
+    } else {
+        user.base_position
+    };
+    let committed: i64 = user
+        .open_orders
+        .iter()
+        .filter(|o| o.is_active && o.side_is_bid == side_is_bid && counts(o))
+        .map(|o| o.total_base_lots())
+        .sum();
+    reducible.max(0) - committed
+}
+
+/// Stores `order` in the first free open-order slot of `user`.
+pub fn rest_order(user: &mut UserAccount, order: Order) -> Result<(), EngineError> {
+    let slot = user
+        .open_orders
+        .iter_mut()
+        .find(|o| !o.is_active)
+        .ok_or(EngineError::OrderSlotsFull)?;
+    *slot = order;
+    Ok(())
 }
 
 pub fn find_user<'a>(users: &'a mut [UserAccount], owner: &Pubkey) -> Option<&'a mut UserAccount> {
@@ -0,0 +1,55 @@
+use crate::error::EngineError;
+use crate::state::UserAccount;
+use solana_program::clock::UnixTimestamp;
+
+/// Moves the matching part of two users' opposite unrealized PnL out of the
+/// winner's quote position into its pending settled balance, paid from the
+/// loser's deposits. The pending amount becomes withdrawable after
+/// `maturity_secs`, counted from the amount-weighted time it was settled.
+/// Returns the settled quote lots.
+pub fn settle_pnl(
+    a: &mut UserAccount,
+    b: &mut UserAccount,
+    oracle_price: i64,
+    now: UnixTimestamp,
+    maturity_secs: i64,
+) -> Result<i64, EngineError> {
+    let pnl_a = a.unrealized_pnl(oracle_price)?;
+    let pnl_b = b.unrealized_pnl(oracle_price)?;
+    let (winner, loser, profit, loss) = if pnl_a > 0 && pnl_b < 0 {
+        (a, b, pnl_a, pnl_b)
+    } else if pnl_b > 0 && pnl_a < 0 {
+        (b, a, pnl_b, pnl_a)
+    } else {
+        return Err(EngineError::NoSettleablePnl);
+    };
+    // Only what the loser still has deposited can back the winner's PnL.
+    let amount = profit.min(-loss).min(loser.net_deposits_lots.max(0));
+    if amount == 0 {
+        return Err(EngineError::NoSettleablePnl);
+    }
+
+    winner.mature_pnl(now, maturity_secs);
+    winner.quote_position = winner
+        .quote_position
+        .checked_sub(amount)
+        .ok_or(EngineError::MathError)?;
+    let pending = winner
+        .pending_pnl_lots
+        .checked_add(amount)
+        .ok_or(EngineError::MathError)?;
+    // Weight the timestamp by amount so a small settlement barely moves the
+    // maturity of a large pending balance.
+    let weighted_ts = (winner.pending_pnl_lots as i128 * winner.pending_pnl_ts as i128
+        + amount as i128 * now as i128)
+        / pending as i128;
+    winner.pending_pnl_lots = pending;
+    winner.pending_pnl_ts = weighted_ts as UnixTimestamp;
+
+    loser.net_deposits_lots = loser
+        .net_deposits_lots
+        .checked_sub(amount)
+        .ok_or(EngineError::MathError)?;
+
+    Ok(amount)
+}
@@ -1,23 +1,146 @@
 use crate::error::EngineError;
-use crate::instruction::EngineInstruction;
-use crate::matching::match_orders;
+use crate::instruction::{
+    EngineInstruction, MarketParamsUpdate, OrderParams, PegParams, TwapParams,
+    MAX_BATCH_MAKER_SLOTS, MAX_BATCH_ORDERS,
+};
+use crate::matching::{
+    cancel_orders, clear_auction, match_orders, prune_expired_orders, reduce_only_capacity,
+    reducing_capacity, rest_order, settle_trade,
+};
 use crate::oracle::{read_price, write_price};
-use crate::queue::{push_event, EventQueueHeader};
-use crate::state::{Market, Order, UserAccount};
-use crate::utils::{assert_rent_exempt, is_zeroed};
-use borsh::{BorshDeserialize, BorshSerialize};
+use crate::pnl::settle_pnl;
+use crate::queue::{consume_events, has_pending_events_for, push_events};
+use crate::state::{
+    Event, Market, MarketAction, MarketKind, MarketStatus, MatchingMode, Order, OrderType, Quote,
+    QuoteRequest, RfqStatus, Side, TriggerDirection, TriggerOrder, TriggerOrders, TwapOrder,
+    TwapStatus, UserAccount,
+};
+use crate::token::{read_token_account, transfer};
+use crate::utils::{
+    assert_admin, assert_owner, assert_owner_or_delegate, assert_rent_exempt, close_account,
+    create_pda_account, find_quote_address, find_quote_request_address,
+    find_trigger_orders_address, find_twap_order_address, find_user_account_address,
+    find_vault_authority, is_zeroed, load_account, load_quote, load_quote_request,
+    load_trigger_orders, load_twap_order, load_user_account, store_account,
+};
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
-    clock::Clock,
+    clock::{Clock, UnixTimestamp},
     entrypoint::ProgramResult,
-    msg,
+    program::invoke,
     program_error::ProgramError,
     pubkey::Pubkey,
+    rent::Rent,
+    system_instruction, system_program,
     sysvar::Sysvar,
 };
 
 pub struct Processor;
 
+/// Deserialized state shared by the order placement instructions.
+///
+/// Accounts: market (writable), user (writable), owner or delegate (signer),
+/// event queue (writable), oracle, followed by maker user accounts (writable).
+struct OrderContext<'a, 'b> {
+    market_ai: &'b AccountInfo<'a>,
+    user_ai: &'b AccountInfo<'a>,
+    event_queue_ai: &'b AccountInfo<'a>,
+    maker_ais: &'b [AccountInfo<'a>],
+    market: Market,
+    taker: UserAccount,
+    makers: Vec<UserAccount>,
+    oracle_price: i64,
+    now: UnixTimestamp,
+    events: Vec<Event>,
+}
+
+impl<'a, 'b> OrderContext<'a, 'b> {
+    /// Loads the standard placement accounts and checks that the authority
+    /// may trade for the user.
+    fn load(program_id: &Pubkey, accounts: &'b [AccountInfo<'a>]) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let market_ai = next_account_info(account_info_iter)?;
+        let user_ai = next_account_info(account_info_iter)?;
+        let authority_ai = next_account_info(account_info_iter)?;
+        let event_queue_ai = next_account_info(account_info_iter)?;
+        let oracle_ai = next_account_info(account_info_iter)?;
+        let maker_ais = account_info_iter.as_slice();
+
+        let ctx = Self::from_accounts(
+            program_id,
+            market_ai,
+            user_ai,
+            event_queue_ai,
+            oracle_ai,
+            maker_ais,
+        )?;
+        assert_owner_or_delegate(&ctx.taker, authority_ai)?;
+        Ok(ctx)
+    }
+
+    /// Loads placement state without any authority check, for cranks.
+    fn from_accounts(
+        program_id: &Pubkey,
+        market_ai: &'b AccountInfo<'a>,
+        user_ai: &'b AccountInfo<'a>,
+        event_queue_ai: &'b AccountInfo<'a>,
+        oracle_ai: &'b AccountInfo<'a>,
+        maker_ais: &'b [AccountInfo<'a>],
+    ) -> Result<Self, ProgramError> {
+        let market = load_account::<Market>(market_ai, program_id)?;
+
+        if event_queue_ai.key != &market.event_queue || oracle_ai.key != &market.oracle {
+            return Err(EngineError::InvalidAccountAddress.into());
+        }
+        // Each account is loaded and stored once; a repeated maker would
+        // let one resting order fill twice.
+        for (i, ai) in maker_ais.iter().enumerate() {
+            if ai.key == user_ai.key || maker_ais[..i].iter().any(|other| other.key == ai.key) {
+                return Err(EngineError::InvalidAccountData.into());
+            }
+        }
+
+        let taker = load_user_account(user_ai, program_id, market_ai.key)?;
+
+        let makers = maker_ais
+            .iter()
+            .map(|ai| load_user_account(ai, program_id, market_ai.key))
+            .collect::<Result<_, _>>()?;
+
+        let oracle_price = read_price(oracle_ai, program_id)?.price;
+
+        Ok(Self {
+            market_ai,
+            user_ai,
+            event_queue_ai,
+            maker_ais,
+            market,
+            taker,
+            makers,
+            oracle_price,
+            now: Clock::get()?.unix_timestamp,
+            events: Vec::with_capacity(16),
+        })
+    }
+
+    /// Writes back all loaded accounts and pushes the collected events.
+    fn commit(mut self, program_id: &Pubkey) -> ProgramResult {
+        self.taker.last_update_ts = self.now;
+        self.taker.update_locked_lots(self.market.kind);
+        for maker in self.makers.iter_mut() {
+            maker.update_locked_lots(self.market.kind);
+        }
+
+        store_account(self.market_ai, &self.market)?;
+        store_account(self.user_ai, &self.taker)?;
+        for (ai, user) in self.maker_ais.iter().zip(self.makers.iter()) {
+            store_account(ai, user)?;
+        }
+
+        push_events(program_id, self.event_queue_ai, &self.events)
+    }
+}
+
 impl Processor {
     pub fn process(
         program_id: &Pubkey,
@@ -26,9 +149,23 @@ impl Processor {
     ) -> ProgramResult {
         let instruction = EngineInstruction::unpack(data)?;
         match instruction {
-            EngineInstruction::InitializeMarket { fee_bps } => {
-                Self::process_initialize_market(program_id, accounts, fee_bps)
-            }
+            EngineInstruction::InitializeMarket {
+                fee_bps,
+                base_lot_size,
+                quote_lot_size,
+                tick_size,
+                min_base_order_size,
+                kind,
+            } => Self::process_initialize_market(
+                program_id,
+                accounts,
+                fee_bps,
+                base_lot_size,
+                quote_lot_size,
+                tick_size,
+                min_base_order_size,
+                kind,
+            ),
             EngineInstruction::Deposit { amount } => {
                 Self::process_deposit(program_id, accounts, amount)
             }
@@ -39,63 +176,232 @@ impl Processor {
                 price_lots,
                 max_base_lots,
                 side_is_bid,
+                order_type,
+                expiry_timestamp,
+                reduce_only,
+                peg,
+                display_base_lots,
             } => Self::process_place_order(
                 program_id,
                 accounts,
-                price_lots,
-                max_base_lots,
-                side_is_bid,
+                OrderParams {
+                    price_lots,
+                    max_base_lots,
+                    side_is_bid,
+                    order_type,
+                    expiry_timestamp,
+                    reduce_only,
+                    peg,
+                    display_base_lots,
+                },
             ),
             EngineInstruction::CancelOrder { order_id } => {
                 Self::process_cancel_order(program_id, accounts, order_id)
             }
             EngineInstruction::UpdateOracle { price, confidence } => {
-                Self::process_update_oracle(accounts, price, confidence)
+                Self::process_update_oracle(program_id, accounts, price, confidence)
             }
             EngineInstruction::Liquidate { max_liq_amount } => {
                 Self::process_liquidate(program_id, accounts, max_liq_amount)
             }
+            EngineInstruction::CreateUserAccount {
+                account_num,
+                max_orders,
+            } => Self::process_create_user_account(program_id, accounts, account_num, max_orders),
+            EngineInstruction::CloseUserAccount => {
+                Self::process_close_user_account(program_id, accounts)
+            }
+            EngineInstruction::ConsumeEvents { limit } => {
+                Self::process_consume_events(program_id, accounts, limit)
+            }
+            EngineInstruction::ResizeUserAccount { max_orders } => {
+                Self::process_resize_user_account(program_id, accounts, max_orders)
+            }
+            EngineInstruction::SetDelegate { delegate } => {
+                Self::process_set_delegate(program_id, accounts, delegate)
+            }
+            EngineInstruction::UpdateMarketParams { params } => {
+                Self::process_update_market_params(program_id, accounts, params)
+            }
+            EngineInstruction::ProposeAdmin { new_admin } => {
+                Self::process_propose_admin(program_id, accounts, new_admin)
+            }
+            EngineInstruction::AcceptAdmin => Self::process_accept_admin(program_id, accounts),
+            EngineInstruction::SetMarketStatus { status } => {
+                Self::process_set_market_status(program_id, accounts, status)
+            }
+            EngineInstruction::CancelAllOrders { side, limit } => {
+                Self::process_cancel_all_orders(program_id, accounts, side, limit)
+            }
+            EngineInstruction::ReplaceOrder {
+                order_id,
+                new_price_lots,
+                new_base_lots,
+            } => Self::process_replace_order(
+                program_id,
+                accounts,
+                order_id,
+                new_price_lots,
+                new_base_lots,
+            ),
+            EngineInstruction::PlaceOrders {
+                orders,
+                cancel_existing,
+            } => Self::process_place_orders(program_id, accounts, orders, cancel_existing),
+            EngineInstruction::PruneExpiredOrders { limit } => {
+                Self::process_prune_expired_orders(program_id, accounts, limit)
+            }
+            EngineInstruction::PlaceTriggerOrder {
+                trigger_price,
+                direction,
+                params,
+            } => Self::process_place_trigger_order(
+                program_id,
+                accounts,
+                trigger_price,
+                direction,
+                params,
+            ),
+            EngineInstruction::CancelTriggerOrder { trigger_id } => {
+                Self::process_cancel_trigger_order(program_id, accounts, trigger_id)
+            }
+            EngineInstruction::ExecuteTriggerOrder { trigger_id } => {
+                Self::process_execute_trigger_order(program_id, accounts, trigger_id)
+            }
+            EngineInstruction::PlaceTwapOrder { nonce, params } => {
+                Self::process_place_twap_order(program_id, accounts, nonce, params)
+            }
+            EngineInstruction::CancelTwapOrder => {
+                Self::process_cancel_twap_order(program_id, accounts)
+            }
+            EngineInstruction::ExecuteTwapSlice => {
+                Self::process_execute_twap_slice(program_id, accounts)
+            }
+            EngineInstruction::ClearAuction => Self::process_clear_auction(program_id, accounts),
+            EngineInstruction::OpenMarket => Self::process_open_market(program_id, accounts),
+            EngineInstruction::CreateQuoteRequest {
+                nonce,
+                side_is_bid,
+                base_lots,
+                expiry_ts,
+            } => Self::process_create_quote_request(
+                program_id,
+                accounts,
+                nonce,
+                side_is_bid,
+                base_lots,
+                expiry_ts,
+            ),
+            EngineInstruction::PostQuote {
+                price_lots,
+                expiry_ts,
+            } => Self::process_post_quote(program_id, accounts, price_lots, expiry_ts),
+            EngineInstruction::AcceptQuote => Self::process_accept_quote(program_id, accounts),
+            EngineInstruction::CancelQuoteRequest => {
+                Self::process_cancel_quote_request(program_id, accounts)
+            }
+            EngineInstruction::SettlePnl => Self::process_settle_pnl(program_id, accounts),
+            EngineInstruction::DepositFunds {
+                base_amount,
+                quote_amount,
+            } => Self::process_deposit_funds(program_id, accounts, base_amount, quote_amount),
+            EngineInstruction::SettleFunds => Self::process_settle_funds(program_id, accounts),
+            EngineInstruction::CancelQuote => Self::process_cancel_quote(program_id, accounts),
+            EngineInstruction::CloseTwapOrder => {
+                Self::process_close_twap_order(program_id, accounts)
+            }
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn process_initialize_market(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
         fee_bps: u16,
+        base_lot_size: u64,
+        quote_lot_size: u64,
+        tick_size: i64,
+        min_base_order_size: i64,
+        kind: MarketKind,
     ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
         let market_ai = next_account_info(account_info_iter)?;
         let admin_ai = next_account_info(account_info_iter)?;
         let oracle_ai = next_account_info(account_info_iter)?;
+        let event_queue_ai = next_account_info(account_info_iter)?;
 
-        if market_ai.owner != program_id {
+        if market_ai.owner != program_id || event_queue_ai.owner != program_id {
             return Err(EngineError::InvalidOwner.into());
         }
+        if !admin_ai.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if !is_zeroed(market_ai) {
+            return Err(EngineError::AlreadyInitialized.into());
+        }
 
         assert_rent_exempt(market_ai)?;
 
-        let mut market = if is_zeroed(market_ai) {
-            Market {
-                admin: *admin_ai.key,
-                base_mint: Pubkey::default(),
-                quote_mint: Pubkey::default(),
-                oracle: *oracle_ai.key,
-                fee_bps,
-                is_active: true,
-                padding: [0; 5],
+        let (vault_authority, vault_bump) = find_vault_authority(program_id, market_ai.key);
+        let mut base_mint = Pubkey::default();
+        let mut quote_mint = Pubkey::default();
+        let mut base_vault = Pubkey::default();
+        let mut quote_vault = Pubkey::default();
+        if kind == MarketKind::Spot {
+            let base_mint_ai = next_account_info(account_info_iter)?;
+            let quote_mint_ai = next_account_info(account_info_iter)?;
+            let base_vault_ai = next_account_info(account_info_iter)?;
+            let quote_vault_ai = next_account_info(account_info_iter)?;
+
+            for (vault_ai, mint_ai) in [
+                (base_vault_ai, base_mint_ai),
+                (quote_vault_ai, quote_mint_ai),
+            ] {
+                let vault = read_token_account(vault_ai)?;
+                if &vault.mint != mint_ai.key || vault.owner != vault_authority {
+                    return Err(EngineError::InvalidAccountData.into());
+                }
             }
-        } else {
-            Market::try_from_slice(&market_ai.try_borrow_data()?)
-                .map_err(|_| ProgramError::InvalidAccountData)?
-        };
+            if base_mint_ai.key == quote_mint_ai.key {
+                return Err(EngineError::InvalidMarketParams.into());
+            }
+            base_mint = *base_mint_ai.key;
+            quote_mint = *quote_mint_ai.key;
+            base_vault = *base_vault_ai.key;
+            quote_vault = *quote_vault_ai.key;
+        }
 
-        market.fee_bps = fee_bps;
-        market.oracle = *oracle_ai.key;
-        market.is_active = true;
+        let market = Market {
+            admin: *admin_ai.key,
+            pending_admin: Pubkey::default(),
+            base_mint,
+            quote_mint,
+            oracle: *oracle_ai.key,
+            event_queue: *event_queue_ai.key,
+            fee_bps,
+            init_margin_bps: Market::DEFAULT_INIT_MARGIN_BPS,
+            maint_margin_bps: Market::DEFAULT_MAINT_MARGIN_BPS,
+            status: MarketStatus::Active,
+            padding: [0; 5],
+            tick_size,
+            base_lot_size,
+            quote_lot_size,
+            min_base_order_size,
+            max_price_deviation_bps: 0,
+            keeper_fee_lots: Market::DEFAULT_KEEPER_FEE_LOTS,
+            matching_mode: MatchingMode::Continuous,
+            auction_interval_secs: 0,
+            auction_end_ts: 0,
+            pnl_maturity_secs: Market::DEFAULT_PNL_MATURITY_SECS,
+            kind,
+            base_vault,
+            quote_vault,
+            vault_bump,
+            seq_num: 0,
+        };
+        market.validate_params()?;
 
-        market
-            .serialize(&mut &mut *market_ai.try_borrow_mut_data()?)
-            .map_err(|_| EngineError::InvalidAccountData.into())
+        store_account(market_ai, &market)
     }
 
     fn process_deposit(
@@ -106,37 +412,24 @@ impl Processor {
         let account_info_iter = &mut accounts.iter();
         let market_ai = next_account_info(account_info_iter)?;
         let user_ai = next_account_info(account_info_iter)?;
-        let owner_ai = next_account_info(account_info_iter)?;
-
-        if market_ai.owner != program_id || user_ai.owner != program_id {
-            return Err(EngineError::InvalidOwner.into());
-        }
 
-        let _market =
-            Market::try_from_slice(&market_ai.try_borrow_data()?).map_err(|_| {
-                msg!("failed to deserialize market");
-                ProgramError::InvalidAccountData
-            })?;
-
-        let mut user = if is_zeroed(user_ai) {
-            UserAccount {
-                owner: *owner_ai.key,
-                market: *market_ai.key,
-                base_position: 0,
-                quote_position: 0,
-                last_update_ts: Clock::get()?.unix_timestamp,
-                open_orders: [Order::default(); 8],
-            }
-        } else {
-            UserAccount::try_from_slice(&user_ai.try_borrow_data()?)
-                .map_err(|_| ProgramError::InvalidAccountData)?
-        };
+        let market = load_account::<Market>(market_ai, program_id)?;
+        market.check_allowed(MarketAction::Deposit)?;
+        market.require_kind(MarketKind::Perp)?;
+        let mut user = load_user_account(user_ai, program_id, market_ai.key)?;
 
-        user.quote_position += amount as i64;
+        let quote_lots = market.native_quote_to_lots(amount)?;
+        user.quote_position = user
+            .quote_position
+            .checked_add(quote_lots)
+            .ok_or(EngineError::MathError)?;
+        user.net_deposits_lots = user
+            .net_deposits_lots
+            .checked_add(quote_lots)
+            .ok_or(EngineError::MathError)?;
         user.last_update_ts = Clock::get()?.unix_timestamp;
 
-        user.serialize(&mut &mut *user_ai.try_borrow_mut_data()?)
-            .map_err(|_| ProgramError::InvalidAccountData)
+        store_account(user_ai, &user)
     }
 
     fn process_withdraw(
@@ -147,106 +440,257 @@ impl Processor {
         let account_info_iter = &mut accounts.iter();
         let market_ai = next_account_info(account_info_iter)?;
         let user_ai = next_account_info(account_info_iter)?;
-        let _recipient_ai = next_account_info(account_info_iter)?;
+        let owner_ai = next_account_info(account_info_iter)?;
+        let oracle_ai = next_account_info(account_info_iter)?;
 
-        if market_ai.owner != program_id || user_ai.owner != program_id {
-            return Err(EngineError::InvalidOwner.into());
+        let market = load_account::<Market>(market_ai, program_id)?;
+        market.check_allowed(MarketAction::Withdraw)?;
+        market.require_kind(MarketKind::Perp)?;
+        if oracle_ai.key != &market.oracle {
+            return Err(EngineError::InvalidAccountAddress.into());
         }
+        let mut user = load_user_account(user_ai, program_id, market_ai.key)?;
+        assert_owner(&user, owner_ai)?;
 
-        let mut user =
-            UserAccount::try_from_slice(&user_ai.try_borrow_data()?).map_err(|_| {
-                msg!("failed to deserialize user");
-                ProgramError::InvalidAccountData
-            })?;
-
-        user.quote_position -= amount as i64;
-        user.last_update_ts = Clock::get()?.unix_timestamp;
+        let now = Clock::get()?.unix_timestamp;
+        let quote_lots = market.native_quote_to_lots(amount)?;
+        // Matured settled PnL is paid out first; the rest must come from
+        // deposits, so unsettled trading gains cannot be withdrawn. What
+        // remains must still margin the position and resting orders.
+        user.mature_pnl(now, market.pnl_maturity_secs);
+        let from_pnl = quote_lots.min(user.settled_pnl_lots.max(0));
+        let from_deposits = quote_lots - from_pnl;
+        if from_deposits > user.net_deposits_lots.min(user.quote_position) {
+            return Err(EngineError::WithdrawLimitExceeded.into());
+        }
+        user.settled_pnl_lots -= from_pnl;
+        user.quote_position -= from_deposits;
+        user.net_deposits_lots -= from_deposits;
+        if !market.meets_initial_margin(&user, read_price(oracle_ai, program_id)?.price)? {
+            return Err(EngineError::WithdrawLimitExceeded.into());
+        }
+        user.last_update_ts = now;
 
-        user.serialize(&mut &mut *user_ai.try_borrow_mut_data()?)
-            .map_err(|_| ProgramError::InvalidAccountData)
+        store_account(user_ai, &user)
     }
 
     fn process_place_order(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        price_lots: i64,
-        max_base_lots: i64,
-        side_is_bid: bool,
+        params: OrderParams,
     ) -> ProgramResult {
-        let account_info_iter = &mut accounts.iter();
-        let market_ai = next_account_info(account_info_iter)?;
-        let user_ai = next_account_info(account_info_iter)?;
-        let event_queue_ai = next_account_info(account_info_iter)?;
-        let remaining_users: Vec<_> = account_info_iter.cloned().collect();
+        let mut ctx = OrderContext::load(program_id, accounts)?;
 
-        if market_ai.owner != program_id || user_ai.owner != program_id {
-            return Err(EngineError::InvalidOwner.into());
+        Self::execute_order(
+            &mut ctx.market,
+            &mut ctx.taker,
+            &mut ctx.makers,
+            &params,
+            ctx.oracle_price,
+            ctx.now,
+            &mut ctx.events,
+        )?;
+
+        ctx.commit(program_id)
+    }
+
+    fn process_place_orders(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        orders: Vec<OrderParams>,
+        cancel_existing: bool,
+    ) -> ProgramResult {
+        if orders.len() > MAX_BATCH_ORDERS {
+            return Err(EngineError::TooManyOrders.into());
         }
 
-        let market =
-            Market::try_from_slice(&market_ai.try_borrow_data()?).map_err(|_| {
-                msg!("failed to deserialize market");
-                ProgramError::InvalidAccountData
-            })?;
+        let mut ctx = OrderContext::load(program_id, accounts)?;
+        let maker_slots: usize = ctx.makers.iter().map(|m| m.open_orders.len()).sum();
+        if orders.len().saturating_mul(maker_slots) > MAX_BATCH_MAKER_SLOTS {
+            return Err(EngineError::TooManyOrders.into());
+        }
 
-        if !market.is_active {
-            return Err(EngineError::MarketInactive.into());
+        if cancel_existing {
+            ctx.market.check_allowed(MarketAction::CancelOrder)?;
+            cancel_orders(&mut ctx.taker, None, usize::MAX, &mut ctx.events);
         }
 
-        let mut taker = UserAccount::try_from_slice(&user_ai.try_borrow_data()?).map_err(
-            |_| {
-                msg!("failed to deserialize taker");
-                ProgramError::InvalidAccountData
-            },
-        )?;
+        for params in orders.iter() {
+            Self::execute_order(
+                &mut ctx.market,
+                &mut ctx.taker,
+                &mut ctx.makers,
+                params,
+                ctx.oracle_price,
+                ctx.now,
+                &mut ctx.events,
+            )?;
+        }
 
-        let mut other_users: Vec<UserAccount> = remaining_users
-            .iter()
-            .map(|ai| {
-                UserAccount::try_from_slice(&ai.try_borrow_data()?)
-                    .map_err(|_| ProgramError::InvalidAccountData)
-            })
-            .collect::<Result<_, _>>()?;
+        ctx.commit(program_id)
+    }
 
-        let mut events = Vec::with_capacity(16);
-        let mut max_quote_change = 0i64;
-        match_orders(
-            &mut taker,
-            &mut other_users[..],
+    /// Validates a single order against already loaded state, matches it
+    /// and rests any remainder of a limit order on the taker's account.
+    fn execute_order(
+        market: &mut Market,
+        taker: &mut UserAccount,
+        makers: &mut [UserAccount],
+        params: &OrderParams,
+        oracle_price: i64,
+        now: UnixTimestamp,
+        events: &mut Vec<Event>,
+    ) -> ProgramResult {
+        let OrderParams {
+            price_lots,
             max_base_lots,
-            &mut max_quote_change,
             side_is_bid,
-            &mut events,
-        );
+            order_type,
+            expiry_timestamp,
+            reduce_only,
+            peg,
+            display_base_lots,
+        } = *params;
+
+        let price_lots = match peg {
+            Some(peg) => {
+                market.validate_pegged_order(&peg, max_base_lots)?;
+                Order::pegged_price(
+                    side_is_bid,
+                    peg.offset_lots,
+                    peg.limit_lots.unwrap_or(0),
+                    oracle_price,
+                )
+                .ok_or(EngineError::InvalidOrderParams)?
+            }
+            None => {
+                market.validate_order(price_lots, max_base_lots)?;
+                price_lots
+            }
+        };
+        // A reduce-only market treats every order as reduce-only, capped
+        // after all of the taker's resting orders on that side.
+        let reduce_only_market = market.status == MarketStatus::ReduceOnly;
+        let max_base_lots = if reduce_only || reduce_only_market {
+            let capacity = if reduce_only_market {
+                reducing_capacity(taker, side_is_bid)
+            } else {
+                reduce_only_capacity(taker, side_is_bid)
+            };
+            if capacity <= 0 {
+                return Err(EngineError::OrderNotReducing.into());
+            }
+            max_base_lots.min(capacity)
+        } else {
+            max_base_lots
+        };
+        if display_base_lots.is_some_and(|display| {
+            display < market.min_base_order_size || order_type != OrderType::Limit
+        }) {
+            return Err(EngineError::InvalidOrderParams.into());
+        }
+        let expiry_ts = expiry_timestamp.unwrap_or(0);
+        if expiry_timestamp.is_some_and(|ts| ts <= now) {
+            return Err(EngineError::OrderExpired.into());
+        }
+        let price_lots =
+            market.apply_price_band(price_lots, side_is_bid, order_type, oracle_price)?;
+        if market.kind == MarketKind::Spot && (peg.is_some() || reduce_only) {
+            return Err(EngineError::InvalidOrderParams.into());
+        }
+        let reducing = max_base_lots <= reducing_capacity(taker, side_is_bid);
 
-        taker.last_update_ts = Clock::get()?.unix_timestamp;
+        // Spot orders must be funded by the balance not already reserved by
+        // the taker's resting orders: fills pay from it and the resting
+        // remainder locks it. Perp orders are checked against the initial
+        // margin once they have matched and rested.
+        taker.update_locked_lots(market.kind);
+        if market.kind == MarketKind::Spot {
+            let (required, free) = if side_is_bid {
+                (
+                    max_base_lots
+                        .checked_mul(price_lots)
+                        .ok_or(EngineError::MathError)?,
+                    taker.free_quote_lots(),
+                )
+            } else {
+                (max_base_lots, taker.free_base_lots())
+            };
+            if required > free {
+                return Err(EngineError::InsufficientFunds.into());
+            }
+        }
+        let exposure_before = taker.max_exposure_lots();
 
-        taker.serialize(&mut &mut *user_ai.try_borrow_mut_data()?)
-            .map_err(|_| ProgramError::InvalidAccountData)?;
+        if reducing {
+            market.check_allowed(MarketAction::PlaceReducingOrder)?;
+        } else {
+            market.check_allowed(MarketAction::PlaceOrder)?;
+        }
 
-        for (ai, user) in remaining_users.iter().zip(other_users.iter()) {
-            user.serialize(&mut &mut *ai.try_borrow_mut_data()?)
-                .map_err(|_| ProgramError::InvalidAccountData)?;
+        // Batch auction and pre-open orders only rest; they cross when the
+        // auction clears.
+        let batch = market.matching_mode == MatchingMode::BatchAuction
+            || market.status == MarketStatus::PreOpen;
+        if batch && order_type != OrderType::Limit {
+            return Err(EngineError::InvalidMatchingMode.into());
         }
 
-        let mut header =
-            EventQueueHeader::try_from_slice(&event_queue_ai.try_borrow_data()?[..24])
-                .unwrap_or(EventQueueHeader {
-                    head: 0,
-                    tail: 0,
-                    capacity: 64,
-                });
+        let mut max_quote_change = 0i64;
+        let remaining_base_lots = if batch {
+            max_base_lots
+        } else {
+            match_orders(
+                taker,
+                makers,
+                price_lots,
+                max_base_lots,
+                &mut max_quote_change,
+                side_is_bid,
+                oracle_price,
+                &mut market.seq_num,
+                now,
+                events,
+            )
+        };
 
-        let mut buf = event_queue_ai.try_borrow_mut_data()?;
-        let (_, data_region) = buf.split_at_mut(24);
+        if remaining_base_lots > 0 && order_type == OrderType::Limit {
+            let display_base_lots = display_base_lots.unwrap_or(0);
+            let visible_base_lots = if display_base_lots > 0 {
+                remaining_base_lots.min(display_base_lots)
+            } else {
+                remaining_base_lots
+            };
+            rest_order(
+                taker,
+                Order {
+                    id: market.seq_num as u128,
+                    price_lots,
+                    base_lots: visible_base_lots,
+                    side_is_bid,
+                    is_active: true,
+                    expiry_ts,
+                    reduce_only: reduce_only || reduce_only_market,
+                    is_pegged: peg.is_some(),
+                    peg_offset_lots: peg.map_or(0, |p| p.offset_lots),
+                    peg_limit_lots: peg.and_then(|p| p.limit_lots).unwrap_or(0),
+                    display_base_lots,
+                    hidden_base_lots: remaining_base_lots - visible_base_lots,
+                },
+            )?;
+            market.seq_num += 1;
+        }
 
-        for event in events.iter() {
-            push_event(&mut header, &mut data_region[..], event)?;
+        // An order that can only shrink the taker's worst-case position is
+        // always allowed; anything else must leave it initially margined.
+        if market.kind == MarketKind::Perp
+            && taker.max_exposure_lots() > exposure_before
+            && !market.meets_initial_margin(taker, oracle_price)?
+        {
+            return Err(EngineError::InsufficientFunds.into());
         }
 
-        header
-            .serialize(&mut &mut event_queue_ai.try_borrow_mut_data()?[..24])
-            .map_err(|_| ProgramError::InvalidAccountData)
+        Ok(())
     }
 
     fn process_cancel_order(
@@ -257,29 +701,27 @@ impl Processor {
         let account_info_iter = &mut accounts.iter();
         let market_ai = next_account_info(account_info_iter)?;
         let user_ai = next_account_info(account_info_iter)?;
+        let authority_ai = next_account_info(account_info_iter)?;
 
-        if market_ai.owner != program_id || user_ai.owner != program_id {
-            return Err(EngineError::InvalidOwner.into());
-        }
-
-        let mut user =
-            UserAccount::try_from_slice(&user_ai.try_borrow_data()?).map_err(|_| {
-                msg!("failed to deserialize user");
-                ProgramError::InvalidAccountData
-            })?;
+        let market = load_account::<Market>(market_ai, program_id)?;
+        market.check_allowed(MarketAction::CancelOrder)?;
+        let mut user = load_user_account(user_ai, program_id, market_ai.key)?;
+        assert_owner_or_delegate(&user, authority_ai)?;
 
         for order in user.open_orders.iter_mut() {
             if order.id == order_id {
                 order.is_active = false;
                 order.base_lots = 0;
+                order.hidden_base_lots = 0;
             }
         }
+        user.update_locked_lots(market.kind);
 
-        user.serialize(&mut &mut *user_ai.try_borrow_mut_data()?)
-            .map_err(|_| ProgramError::InvalidAccountData)
+        store_account(user_ai, &user)
     }
 
     fn process_update_oracle(
+        program_id: &Pubkey,
         accounts: &[AccountInfo],
         price: i64,
         confidence: u64,
@@ -287,7 +729,7 @@ impl Processor {
         let account_info_iter = &mut accounts.iter();
         let oracle_ai = next_account_info(account_info_iter)?;
 
-        write_price(oracle_ai, price, confidence)
+        write_price(oracle_ai, program_id, price, confidence)
     }
 
     fn process_liquidate(
@@ -301,31 +743,17 @@ impl Processor {
         let liqee_ai = next_account_info(account_info_iter)?;
         let oracle_ai = next_account_info(account_info_iter)?;
 
-        if market_ai.owner != program_id
-            || liqor_ai.owner != program_id
-            || liqee_ai.owner != program_id
-        {
-            return Err(EngineError::InvalidOwner.into());
+        let market = load_account::<Market>(market_ai, program_id)?;
+        market.check_allowed(MarketAction::Liquidate)?;
+        market.require_kind(MarketKind::Perp)?;
+        if oracle_ai.key != &market.oracle {
+            return Err(EngineError::InvalidAccountAddress.into());
         }
 
-        let _market =
-            Market::try_from_slice(&market_ai.try_borrow_data()?).map_err(|_| {
-                msg!("failed to deserialize market");
-                ProgramError::InvalidAccountData
-            })?;
-
-        let price = read_price(oracle_ai)?.price;
+        let price = read_price(oracle_ai, program_id)?.price;
 
-        let mut liqor =
-            UserAccount::try_from_slice(&liqor_ai.try_borrow_data()?).map_err(|_| {
-                msg!("failed to deserialize liqor");
-                ProgramError::InvalidAccountData
-            })?;
-        let mut liqee =
-            UserAccount::try_from_slice(&liqee_ai.try_borrow_data()?).map_err(|_| {
-                msg!("failed to deserialize liqee");
-                ProgramError::InvalidAccountData
-            })?;
+        let mut liqor = load_user_account(liqor_ai, program_id, market_ai.key)?;
+        let mut liqee = load_user_account(liqee_ai, program_id, market_ai.key)?;
 
         let max_base = max_liq_amount as i64;
         let quote_change = max_base * price;
@@ -335,9 +763,1264 @@ impl Processor {
         liqee.base_position -= max_base;
         liqee.quote_position += quote_change;
 
-        liqor.serialize(&mut &mut *liqor_ai.try_borrow_mut_data()?)
-            .map_err(|_| ProgramError::InvalidAccountData)?;
-        liqee.serialize(&mut &mut *liqee_ai.try_borrow_mut_data()?)
-            .map_err(|_| ProgramError::InvalidAccountData)
+        store_account(liqor_ai, &liqor)?;
+        store_account(liqee_ai, &liqee)
+    }
+
+    fn process_create_user_account(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        account_num: u32,
+        max_orders: u16,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let market_ai = next_account_info(account_info_iter)?;
+        let user_ai = next_account_info(account_info_iter)?;
+        let owner_ai = next_account_info(account_info_iter)?;
+        let system_program_ai = next_account_info(account_info_iter)?;
+
+        if !owner_ai.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if system_program_ai.key != &system_program::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let market = load_account::<Market>(market_ai, program_id)?;
+        market.check_allowed(MarketAction::CreateUserAccount)?;
+
+        let max_orders = max_orders as usize;
+        if max_orders == 0 || max_orders > UserAccount::MAX_OPEN_ORDERS {
+            return Err(EngineError::InvalidOrderCapacity.into());
+        }
+
+        let (address, bump) =
+            find_user_account_address(program_id, market_ai.key, owner_ai.key, account_num);
+        if &address != user_ai.key {
+            return Err(EngineError::InvalidAccountAddress.into());
+        }
+        if user_ai.owner == program_id {
+            return Err(EngineError::AlreadyInitialized.into());
+        }
+
+        create_pda_account(
+            program_id,
+            owner_ai,
+            user_ai,
+            system_program_ai,
+            UserAccount::space(max_orders),
+            &[
+                market_ai.key.as_ref(),
+                owner_ai.key.as_ref(),
+                &account_num.to_le_bytes(),
+                &[bump],
+            ],
+        )?;
+
+        let user = UserAccount {
+            owner: *owner_ai.key,
+            market: *market_ai.key,
+            delegate: Pubkey::default(),
+            account_num,
+            bump,
+            base_position: 0,
+            quote_position: 0,
+            last_update_ts: Clock::get()?.unix_timestamp,
+            net_deposits_lots: 0,
+            settled_pnl_lots: 0,
+            pending_pnl_lots: 0,
+            pending_pnl_ts: 0,
+            locked_base_lots: 0,
+            locked_quote_lots: 0,
+            open_orders: vec![Order::default(); max_orders],
+        };
+
+        store_account(user_ai, &user)
+    }
+
+    fn process_close_user_account(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let market_ai = next_account_info(account_info_iter)?;
+        let user_ai = next_account_info(account_info_iter)?;
+        let owner_ai = next_account_info(account_info_iter)?;
+        let event_queue_ai = next_account_info(account_info_iter)?;
+
+        let market = load_account::<Market>(market_ai, program_id)?;
+        let user = load_user_account(user_ai, program_id, market_ai.key)?;
+        assert_owner(&user, owner_ai)?;
+
+        if event_queue_ai.key != &market.event_queue {
+            return Err(EngineError::InvalidAccountAddress.into());
+        }
+
+        if user.base_position != 0
+            || user.quote_position != 0
+            || user.settled_pnl_lots != 0
+            || user.pending_pnl_lots != 0
+            || user.open_orders.iter().any(|o| o.is_active)
+            || has_pending_events_for(program_id, event_queue_ai, &user.owner, user.account_num)?
+        {
+            return Err(EngineError::AccountNotEmpty.into());
+        }
+
+        close_account(user_ai, owner_ai)
+    }
+
+    fn process_consume_events(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        limit: u16,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let market_ai = next_account_info(account_info_iter)?;
+        let admin_ai = next_account_info(account_info_iter)?;
+        let event_queue_ai = next_account_info(account_info_iter)?;
+
+        let market = load_account::<Market>(market_ai, program_id)?;
+        // Consumed events are gone for good, and pending ones keep user
+        // accounts from closing, so only the admin's crank may pop them.
+        assert_admin(&market, admin_ai)?;
+        if event_queue_ai.key != &market.event_queue {
+            return Err(EngineError::InvalidAccountAddress.into());
+        }
+
+        consume_events(program_id, event_queue_ai, limit as u64)
+    }
+
+    fn process_resize_user_account(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        max_orders: u16,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let market_ai = next_account_info(account_info_iter)?;
+        let user_ai = next_account_info(account_info_iter)?;
+        let owner_ai = next_account_info(account_info_iter)?;
+        let system_program_ai = next_account_info(account_info_iter)?;
+
+        let _market = load_account::<Market>(market_ai, program_id)?;
+        let mut user = load_user_account(user_ai, program_id, market_ai.key)?;
+        assert_owner(&user, owner_ai)?;
+
+        if system_program_ai.key != &system_program::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let max_orders = max_orders as usize;
+        if max_orders <= user.open_orders.len() || max_orders > UserAccount::MAX_OPEN_ORDERS {
+            return Err(EngineError::InvalidOrderCapacity.into());
+        }
+
+        let new_len = UserAccount::space(max_orders);
+        let required = Rent::get()?.minimum_balance(new_len);
+        if user_ai.lamports() < required {
+            invoke(
+                &system_instruction::transfer(
+                    owner_ai.key,
+                    user_ai.key,
+                    required - user_ai.lamports(),
+                ),
+                &[owner_ai.clone(), user_ai.clone(), system_program_ai.clone()],
+            )?;
+        }
+        user_ai.realloc(new_len, true)?;
+
+        user.open_orders.resize(max_orders, Order::default());
+        store_account(user_ai, &user)
+    }
+
+    fn process_set_delegate(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        delegate: Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let market_ai = next_account_info(account_info_iter)?;
+        let user_ai = next_account_info(account_info_iter)?;
+        let owner_ai = next_account_info(account_info_iter)?;
+
+        let _market = load_account::<Market>(market_ai, program_id)?;
+        let mut user = load_user_account(user_ai, program_id, market_ai.key)?;
+        assert_owner(&user, owner_ai)?;
+
+        user.delegate = delegate;
+        store_account(user_ai, &user)
+    }
+
+    fn process_update_market_params(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        params: MarketParamsUpdate,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let market_ai = next_account_info(account_info_iter)?;
+        let admin_ai = next_account_info(account_info_iter)?;
+        let event_queue_ai = next_account_info(account_info_iter)?;
+
+        let mut market = load_account::<Market>(market_ai, program_id)?;
+        assert_admin(&market, admin_ai)?;
+        if event_queue_ai.key != &market.event_queue {
+            return Err(EngineError::InvalidAccountAddress.into());
+        }
+
+        let previous_mode = market.matching_mode;
+        params.apply(&mut market);
+        market.validate_params()?;
+        if market.matching_mode == MatchingMode::BatchAuction
+            && previous_mode != MatchingMode::BatchAuction
+        {
+            market.auction_end_ts = Clock::get()?.unix_timestamp + market.auction_interval_secs;
+        }
+
+        store_account(market_ai, &market)?;
+
+        let event = Event::MarketParamsUpdated {
+            market: *market_ai.key,
+            fee_bps: market.fee_bps,
+            oracle: market.oracle,
+            init_margin_bps: market.init_margin_bps,
+            maint_margin_bps: market.maint_margin_bps,
+            tick_size: market.tick_size,
+        };
+        push_events(program_id, event_queue_ai, &[event])
+    }
+
+    fn process_propose_admin(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        new_admin: Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let market_ai = next_account_info(account_info_iter)?;
+        let admin_ai = next_account_info(account_info_iter)?;
+
+        let mut market = load_account::<Market>(market_ai, program_id)?;
+        assert_admin(&market, admin_ai)?;
+
+        market.pending_admin = new_admin;
+        store_account(market_ai, &market)
+    }
+
+    fn process_accept_admin(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let market_ai = next_account_info(account_info_iter)?;
+        let pending_admin_ai = next_account_info(account_info_iter)?;
+
+        let mut market = load_account::<Market>(market_ai, program_id)?;
+        if market.pending_admin == Pubkey::default()
+            || !pending_admin_ai.is_signer
+            || pending_admin_ai.key != &market.pending_admin
+        {
+            return Err(EngineError::Unauthorized.into());
+        }
+
+        market.admin = market.pending_admin;
+        market.pending_admin = Pubkey::default();
+        store_account(market_ai, &market)
+    }
+
+    fn process_set_market_status(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        status: MarketStatus,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let market_ai = next_account_info(account_info_iter)?;
+        let admin_ai = next_account_info(account_info_iter)?;
+
+        let mut market = load_account::<Market>(market_ai, program_id)?;
+        assert_admin(&market, admin_ai)?;
+
+        if !market.status.can_transition_to(status) {
+            return Err(EngineError::InvalidStatusTransition.into());
+        }
+
+        market.status = status;
+        store_account(market_ai, &market)
+    }
+
+    fn process_cancel_all_orders(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        side: Option<Side>,
+        limit: u8,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let market_ai = next_account_info(account_info_iter)?;
+        let user_ai = next_account_info(account_info_iter)?;
+        let authority_ai = next_account_info(account_info_iter)?;
+        let event_queue_ai = next_account_info(account_info_iter)?;
+
+        let market = load_account::<Market>(market_ai, program_id)?;
+        market.check_allowed(MarketAction::CancelOrder)?;
+        if event_queue_ai.key != &market.event_queue {
+            return Err(EngineError::InvalidAccountAddress.into());
+        }
+
+        let mut user = load_user_account(user_ai, program_id, market_ai.key)?;
+        assert_owner_or_delegate(&user, authority_ai)?;
+
+        let mut events = Vec::new();
+        cancel_orders(&mut user, side, limit as usize, &mut events);
+        user.update_locked_lots(market.kind);
+
+        store_account(user_ai, &user)?;
+        push_events(program_id, event_queue_ai, &events)
+    }
+
+    fn process_replace_order(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        order_id: u128,
+        new_price_lots: i64,
+        new_base_lots: i64,
+    ) -> ProgramResult {
+        let mut ctx = OrderContext::load(program_id, accounts)?;
+
+        let order = ctx
+            .taker
+            .open_orders
+            .iter_mut()
+            .find(|o| o.is_active && o.id == order_id)
+            .ok_or(EngineError::OrderNotFound)?;
+
+        if (order.is_pegged || new_price_lots == order.price_lots)
+            && new_base_lots <= order.total_base_lots()
+        {
+            // Shrinking in place keeps the order id and hence its priority.
+            ctx.market.check_allowed(MarketAction::CancelOrder)?;
+            if order.is_pegged {
+                if new_base_lots < ctx.market.min_base_order_size {
+                    return Err(EngineError::InvalidOrderParams.into());
+                }
+            } else {
+                ctx.market.validate_order(new_price_lots, new_base_lots)?;
+            }
+            order.base_lots = order.base_lots.min(new_base_lots);
+            order.hidden_base_lots = new_base_lots - order.base_lots;
+            return ctx.commit(program_id);
+        }
+
+        ctx.events.push(Event::Out {
+            owner: ctx.taker.owner,
+            account_num: ctx.taker.account_num,
+            order_id: order.id,
+            side_is_bid: order.side_is_bid,
+            base_lots: order.total_base_lots(),
+        });
+        let side_is_bid = order.side_is_bid;
+        let expiry_timestamp = (order.expiry_ts != 0).then_some(order.expiry_ts);
+        let reduce_only = order.reduce_only;
+        let peg = order.is_pegged.then_some(PegParams {
+            offset_lots: order.peg_offset_lots,
+            limit_lots: (order.peg_limit_lots != 0).then_some(order.peg_limit_lots),
+        });
+        let display_base_lots = (order.display_base_lots != 0).then_some(order.display_base_lots);
+        order.is_active = false;
+        order.base_lots = 0;
+        order.hidden_base_lots = 0;
+
+        let params = OrderParams {
+            price_lots: new_price_lots,
+            max_base_lots: new_base_lots,
+            side_is_bid,
+            order_type: OrderType::Limit,
+            expiry_timestamp,
+            reduce_only,
+            peg,
+            display_base_lots,
+        };
+        Self::execute_order(
+            &mut ctx.market,
+            &mut ctx.taker,
+            &mut ctx.makers,
+            &params,
+            ctx.oracle_price,
+            ctx.now,
+            &mut ctx.events,
+        )?;
+
+        ctx.commit(program_id)
+    }
+
+    fn process_prune_expired_orders(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        limit: u8,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let market_ai = next_account_info(account_info_iter)?;
+        let event_queue_ai = next_account_info(account_info_iter)?;
+        let user_ais = account_info_iter.as_slice();
+
+        let market = load_account::<Market>(market_ai, program_id)?;
+        if event_queue_ai.key != &market.event_queue {
+            return Err(EngineError::InvalidAccountAddress.into());
+        }
+
+        let now = Clock::get()?.unix_timestamp;
+        let mut remaining = limit as usize;
+        let mut events = Vec::new();
+        for user_ai in user_ais {
+            if remaining == 0 {
+                break;
+            }
+            let mut user = load_user_account(user_ai, program_id, market_ai.key)?;
+            let pruned = prune_expired_orders(&mut user, now, remaining, &mut events);
+            if pruned > 0 {
+                user.update_locked_lots(market.kind);
+                store_account(user_ai, &user)?;
+            }
+            remaining -= pruned;
+        }
+
+        push_events(program_id, event_queue_ai, &events)
+    }
+
+    fn process_place_trigger_order(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        trigger_price: i64,
+        direction: TriggerDirection,
+        params: OrderParams,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let market_ai = next_account_info(account_info_iter)?;
+        let user_ai = next_account_info(account_info_iter)?;
+        let authority_ai = next_account_info(account_info_iter)?;
+        let triggers_ai = next_account_info(account_info_iter)?;
+        let system_program_ai = next_account_info(account_info_iter)?;
+
+        let market = load_account::<Market>(market_ai, program_id)?;
+        let user = load_user_account(user_ai, program_id, market_ai.key)?;
+        assert_owner_or_delegate(&user, authority_ai)?;
+
+        match params.peg {
+            Some(peg) => market.validate_pegged_order(&peg, params.max_base_lots)?,
+            None => market.validate_order(params.price_lots, params.max_base_lots)?,
+        }
+        if trigger_price <= 0 {
+            return Err(EngineError::InvalidOrderParams.into());
+        }
+
+        let mut triggers = if triggers_ai.owner == program_id {
+            load_trigger_orders(triggers_ai, program_id, user_ai.key)?
+        } else {
+            if system_program_ai.key != &system_program::id() {
+                return Err(ProgramError::IncorrectProgramId);
+            }
+            let (address, bump) = find_trigger_orders_address(program_id, user_ai.key);
+            if &address != triggers_ai.key {
+                return Err(EngineError::InvalidAccountAddress.into());
+            }
+            create_pda_account(
+                program_id,
+                authority_ai,
+                triggers_ai,
+                system_program_ai,
+                TriggerOrders::LEN,
+                &[b"triggers", user_ai.key.as_ref(), &[bump]],
+            )?;
+            TriggerOrders {
+                user: *user_ai.key,
+                bump,
+                next_id: 0,
+                orders: Vec::new(),
+            }
+        };
+
+        if triggers.orders.len() >= TriggerOrders::MAX_ORDERS {
+            return Err(EngineError::OrderSlotsFull.into());
+        }
+        triggers.orders.push(TriggerOrder {
+            id: triggers.next_id,
+            trigger_price,
+            direction,
+            params,
+        });
+        triggers.next_id += 1;
+
+        store_account(triggers_ai, &triggers)
+    }
+
+    fn process_cancel_trigger_order(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        trigger_id: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let market_ai = next_account_info(account_info_iter)?;
+        let user_ai = next_account_info(account_info_iter)?;
+        let authority_ai = next_account_info(account_info_iter)?;
+        let triggers_ai = next_account_info(account_info_iter)?;
+
+        let _market = load_account::<Market>(market_ai, program_id)?;
+        let user = load_user_account(user_ai, program_id, market_ai.key)?;
+        assert_owner_or_delegate(&user, authority_ai)?;
+
+        let mut triggers = load_trigger_orders(triggers_ai, program_id, user_ai.key)?;
+        let idx = triggers
+            .orders
+            .iter()
+            .position(|t| t.id == trigger_id)
+            .ok_or(EngineError::OrderNotFound)?;
+        triggers.orders.remove(idx);
+
+        store_account(triggers_ai, &triggers)
+    }
+
+    fn process_execute_trigger_order(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        trigger_id: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let market_ai = next_account_info(account_info_iter)?;
+        let user_ai = next_account_info(account_info_iter)?;
+        let triggers_ai = next_account_info(account_info_iter)?;
+        let keeper_ai = next_account_info(account_info_iter)?;
+        let event_queue_ai = next_account_info(account_info_iter)?;
+        let oracle_ai = next_account_info(account_info_iter)?;
+        let maker_ais = account_info_iter.as_slice();
+
+        if keeper_ai.key == user_ai.key || maker_ais.iter().any(|ai| ai.key == keeper_ai.key) {
+            return Err(EngineError::InvalidAccountData.into());
+        }
+
+        let mut ctx = OrderContext::from_accounts(
+            program_id,
+            market_ai,
+            user_ai,
+            event_queue_ai,
+            oracle_ai,
+            maker_ais,
+        )?;
+        let mut keeper = load_user_account(keeper_ai, program_id, market_ai.key)?;
+
+        let mut triggers = load_trigger_orders(triggers_ai, program_id, user_ai.key)?;
+        let idx = triggers
+            .orders
+            .iter()
+            .position(|t| t.id == trigger_id)
+            .ok_or(EngineError::OrderNotFound)?;
+        if !triggers.orders[idx].is_triggered(ctx.oracle_price) {
+            return Err(EngineError::TriggerNotMet.into());
+        }
+        let trigger = triggers.orders.remove(idx);
+
+        let active_orders =
+            |user: &UserAccount| user.open_orders.iter().filter(|o| o.is_active).count();
+        let base_before = ctx.taker.base_position;
+        let resting_before = active_orders(&ctx.taker);
+        Self::execute_order(
+            &mut ctx.market,
+            &mut ctx.taker,
+            &mut ctx.makers,
+            &trigger.params,
+            ctx.oracle_price,
+            ctx.now,
+            &mut ctx.events,
+        )?;
+        // The keeper picks the makers, so a trigger only counts as executed,
+        // and only pays, once its order actually filled or rested.
+        if ctx.taker.base_position == base_before && active_orders(&ctx.taker) == resting_before {
+            return Err(EngineError::OrderNotExecuted.into());
+        }
+
+        // A spot fee must come out of quote the new order left unreserved.
+        let fee = ctx.market.keeper_fee_lots;
+        ctx.taker.update_locked_lots(ctx.market.kind);
+        if ctx.market.kind == MarketKind::Spot && ctx.taker.free_quote_lots() < fee {
+            return Err(EngineError::InsufficientFunds.into());
+        }
+        ctx.taker.quote_position = ctx
+            .taker
+            .quote_position
+            .checked_sub(fee)
+            .ok_or(EngineError::MathError)?;
+        keeper.quote_position = keeper
+            .quote_position
+            .checked_add(fee)
+            .ok_or(EngineError::MathError)?;
+
+        store_account(triggers_ai, &triggers)?;
+        store_account(keeper_ai, &keeper)?;
+        ctx.commit(program_id)
+    }
+
+    fn process_place_twap_order(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        nonce: u64,
+        params: TwapParams,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let market_ai = next_account_info(account_info_iter)?;
+        let user_ai = next_account_info(account_info_iter)?;
+        let authority_ai = next_account_info(account_info_iter)?;
+        let twap_ai = next_account_info(account_info_iter)?;
+        let system_program_ai = next_account_info(account_info_iter)?;
+
+        let market = load_account::<Market>(market_ai, program_id)?;
+        market.check_allowed(MarketAction::PlaceOrder)?;
+        let user = load_user_account(user_ai, program_id, market_ai.key)?;
+        assert_owner_or_delegate(&user, authority_ai)?;
+
+        if params.num_slices == 0
+            || params.interval_secs <= 0
+            || params.total_base_lots < params.num_slices as i64 * market.min_base_order_size
+        {
+            return Err(EngineError::InvalidOrderParams.into());
+        }
+        market.validate_order(params.limit_price_lots, params.total_base_lots)?;
+
+        if system_program_ai.key != &system_program::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let (address, bump) = find_twap_order_address(program_id, user_ai.key, nonce);
+        if &address != twap_ai.key {
+            return Err(EngineError::InvalidAccountAddress.into());
+        }
+        if twap_ai.owner == program_id {
+            return Err(EngineError::AlreadyInitialized.into());
+        }
+        create_pda_account(
+            program_id,
+            authority_ai,
+            twap_ai,
+            system_program_ai,
+            TwapOrder::LEN,
+            &[b"twap", user_ai.key.as_ref(), &nonce.to_le_bytes(), &[bump]],
+        )?;
+
+        let twap = TwapOrder {
+            user: *user_ai.key,
+            nonce,
+            bump,
+            status: TwapStatus::Active,
+            side_is_bid: params.side_is_bid,
+            limit_price_lots: params.limit_price_lots,
+            total_base_lots: params.total_base_lots,
+            filled_base_lots: 0,
+            num_slices: params.num_slices,
+            executed_slices: 0,
+            interval_secs: params.interval_secs,
+            next_slice_ts: Clock::get()?.unix_timestamp,
+        };
+        store_account(twap_ai, &twap)
+    }
+
+    fn process_cancel_twap_order(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let market_ai = next_account_info(account_info_iter)?;
+        let user_ai = next_account_info(account_info_iter)?;
+        let authority_ai = next_account_info(account_info_iter)?;
+        let twap_ai = next_account_info(account_info_iter)?;
+
+        let _market = load_account::<Market>(market_ai, program_id)?;
+        let user = load_user_account(user_ai, program_id, market_ai.key)?;
+        assert_owner_or_delegate(&user, authority_ai)?;
+
+        let mut twap = load_twap_order(twap_ai, program_id, user_ai.key)?;
+        if twap.status != TwapStatus::Active {
+            return Err(EngineError::TwapNotActive.into());
+        }
+        twap.status = TwapStatus::Cancelled;
+
+        store_account(twap_ai, &twap)
+    }
+
+    fn process_close_twap_order(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let market_ai = next_account_info(account_info_iter)?;
+        let user_ai = next_account_info(account_info_iter)?;
+        let authority_ai = next_account_info(account_info_iter)?;
+        let twap_ai = next_account_info(account_info_iter)?;
+        let owner_ai = next_account_info(account_info_iter)?;
+
+        let _market = load_account::<Market>(market_ai, program_id)?;
+        let user = load_user_account(user_ai, program_id, market_ai.key)?;
+        assert_owner_or_delegate(&user, authority_ai)?;
+        if owner_ai.key != &user.owner {
+            return Err(EngineError::InvalidAccountAddress.into());
+        }
+        load_twap_order(twap_ai, program_id, user_ai.key)?;
+
+        close_account(twap_ai, owner_ai)
+    }
+
+    fn process_execute_twap_slice(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let market_ai = next_account_info(account_info_iter)?;
+        let user_ai = next_account_info(account_info_iter)?;
+        let twap_ai = next_account_info(account_info_iter)?;
+        let event_queue_ai = next_account_info(account_info_iter)?;
+        let oracle_ai = next_account_info(account_info_iter)?;
+        let maker_ais = account_info_iter.as_slice();
+
+        let mut ctx = OrderContext::from_accounts(
+            program_id,
+            market_ai,
+            user_ai,
+            event_queue_ai,
+            oracle_ai,
+            maker_ais,
+        )?;
+
+        let mut twap = load_twap_order(twap_ai, program_id, user_ai.key)?;
+        if twap.status != TwapStatus::Active {
+            return Err(EngineError::TwapNotActive.into());
+        }
+        if !twap.is_due(ctx.now) {
+            return Err(EngineError::TwapSliceNotDue.into());
+        }
+
+        let params = OrderParams {
+            price_lots: twap.limit_price_lots,
+            max_base_lots: twap.next_slice_base_lots(ctx.market.min_base_order_size),
+            side_is_bid: twap.side_is_bid,
+            order_type: OrderType::ImmediateOrCancel,
+            expiry_timestamp: None,
+            reduce_only: false,
+            peg: None,
+            display_base_lots: None,
+        };
+        let base_before = ctx.taker.base_position;
+        Self::execute_order(
+            &mut ctx.market,
+            &mut ctx.taker,
+            &mut ctx.makers,
+            &params,
+            ctx.oracle_price,
+            ctx.now,
+            &mut ctx.events,
+        )?;
+        // The crank picks the makers, so a slice only counts once it fills;
+        // otherwise anyone could use up the slices with an empty book.
+        let filled_base_lots = (ctx.taker.base_position - base_before).abs();
+        if filled_base_lots == 0 {
+            return Err(EngineError::OrderNotExecuted.into());
+        }
+        twap.record_slice(filled_base_lots, ctx.market.min_base_order_size);
+
+        store_account(twap_ai, &twap)?;
+        ctx.commit(program_id)
+    }
+
+    fn process_clear_auction(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let market_ai = next_account_info(account_info_iter)?;
+        let admin_ai = next_account_info(account_info_iter)?;
+        let event_queue_ai = next_account_info(account_info_iter)?;
+        let oracle_ai = next_account_info(account_info_iter)?;
+        let user_ais = account_info_iter.as_slice();
+
+        let mut market = load_account::<Market>(market_ai, program_id)?;
+        assert_admin(&market, admin_ai)?;
+        if market.matching_mode != MatchingMode::BatchAuction {
+            return Err(EngineError::InvalidMatchingMode.into());
+        }
+        // A pre-open book is only uncrossed once, by `OpenMarket`.
+        if market.status != MarketStatus::Active {
+            return Err(EngineError::MarketInactive.into());
+        }
+
+        let now = Clock::get()?.unix_timestamp;
+        if now < market.auction_end_ts {
+            return Err(EngineError::AuctionNotDue.into());
+        }
+        market.auction_end_ts = now + market.auction_interval_secs;
+
+        Self::uncross(
+            program_id,
+            market_ai,
+            &mut market,
+            event_queue_ai,
+            oracle_ai,
+            user_ais,
+            now,
+        )
+    }
+
+    fn process_open_market(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let market_ai = next_account_info(account_info_iter)?;
+        let admin_ai = next_account_info(account_info_iter)?;
+        let event_queue_ai = next_account_info(account_info_iter)?;
+        let oracle_ai = next_account_info(account_info_iter)?;
+        let user_ais = account_info_iter.as_slice();
+
+        let mut market = load_account::<Market>(market_ai, program_id)?;
+        assert_admin(&market, admin_ai)?;
+        if market.status != MarketStatus::PreOpen {
+            return Err(EngineError::InvalidStatusTransition.into());
+        }
+        market.status = MarketStatus::Active;
+
+        let now = Clock::get()?.unix_timestamp;
+        Self::uncross(
+            program_id,
+            market_ai,
+            &mut market,
+            event_queue_ai,
+            oracle_ai,
+            user_ais,
+            now,
+        )
+    }
+
+    /// Clears the resting orders of `user_ais` at a single price and writes
+    /// back the market, the users and the resulting events.
+    fn uncross(
+        program_id: &Pubkey,
+        market_ai: &AccountInfo,
+        market: &mut Market,
+        event_queue_ai: &AccountInfo,
+        oracle_ai: &AccountInfo,
+        user_ais: &[AccountInfo],
+        now: UnixTimestamp,
+    ) -> ProgramResult {
+        if event_queue_ai.key != &market.event_queue || oracle_ai.key != &market.oracle {
+            return Err(EngineError::InvalidAccountAddress.into());
+        }
+        for (i, ai) in user_ais.iter().enumerate() {
+            if user_ais[..i].iter().any(|other| other.key == ai.key) {
+                return Err(EngineError::InvalidAccountData.into());
+            }
+        }
+
+        let mut users = user_ais
+            .iter()
+            .map(|ai| load_user_account(ai, program_id, market_ai.key))
+            .collect::<Result<Vec<_>, _>>()?;
+        let oracle_price = read_price(oracle_ai, program_id)?.price;
+
+        let mut events = Vec::new();
+        clear_auction(
+            &mut users,
+            oracle_price,
+            &mut market.seq_num,
+            now,
+            &mut events,
+        );
+
+        store_account(market_ai, market)?;
+        for (ai, user) in user_ais.iter().zip(users.iter_mut()) {
+            user.update_locked_lots(market.kind);
+            store_account(ai, user)?;
+        }
+        push_events(program_id, event_queue_ai, &events)
+    }
+
+    fn process_create_quote_request(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        nonce: u64,
+        side_is_bid: bool,
+        base_lots: i64,
+        expiry_ts: UnixTimestamp,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let market_ai = next_account_info(account_info_iter)?;
+        let user_ai = next_account_info(account_info_iter)?;
+        let authority_ai = next_account_info(account_info_iter)?;
+        let request_ai = next_account_info(account_info_iter)?;
+        let system_program_ai = next_account_info(account_info_iter)?;
+
+        let market = load_account::<Market>(market_ai, program_id)?;
+        let user = load_user_account(user_ai, program_id, market_ai.key)?;
+        assert_owner_or_delegate(&user, authority_ai)?;
+
+        if base_lots < market.min_base_order_size {
+            return Err(EngineError::InvalidOrderParams.into());
+        }
+        if expiry_ts <= Clock::get()?.unix_timestamp {
+            return Err(EngineError::OrderExpired.into());
+        }
+
+        if system_program_ai.key != &system_program::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let (address, bump) = find_quote_request_address(program_id, user_ai.key, nonce);
+        if &address != request_ai.key {
+            return Err(EngineError::InvalidAccountAddress.into());
+        }
+        if request_ai.owner == program_id {
+            return Err(EngineError::AlreadyInitialized.into());
+        }
+        create_pda_account(
+            program_id,
+            authority_ai,
+            request_ai,
+            system_program_ai,
+            QuoteRequest::LEN,
+            &[b"rfq", user_ai.key.as_ref(), &nonce.to_le_bytes(), &[bump]],
+        )?;
+
+        let request = QuoteRequest {
+            user: *user_ai.key,
+            nonce,
+            bump,
+            status: RfqStatus::Open,
+            side_is_bid,
+            base_lots,
+            expiry_ts,
+        };
+        store_account(request_ai, &request)
+    }
+
+    fn process_post_quote(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        price_lots: i64,
+        expiry_ts: UnixTimestamp,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let market_ai = next_account_info(account_info_iter)?;
+        let request_ai = next_account_info(account_info_iter)?;
+        let maker_ai = next_account_info(account_info_iter)?;
+        let authority_ai = next_account_info(account_info_iter)?;
+        let quote_ai = next_account_info(account_info_iter)?;
+        let system_program_ai = next_account_info(account_info_iter)?;
+
+        let market = load_account::<Market>(market_ai, program_id)?;
+        let maker = load_user_account(maker_ai, program_id, market_ai.key)?;
+        assert_owner_or_delegate(&maker, authority_ai)?;
+
+        let request = load_quote_request(request_ai, program_id)?;
+        if &request.user == maker_ai.key {
+            return Err(EngineError::InvalidAccountData.into());
+        }
+        let now = Clock::get()?.unix_timestamp;
+        if !request.is_open(now) {
+            return Err(EngineError::QuoteRequestClosed.into());
+        }
+        market.validate_order(price_lots, request.base_lots)?;
+        if expiry_ts <= now {
+            return Err(EngineError::QuoteExpired.into());
+        }
+
+        let bump = if quote_ai.owner == program_id {
+            load_quote(quote_ai, program_id, request_ai.key)?.bump
+        } else {
+            if system_program_ai.key != &system_program::id() {
+                return Err(ProgramError::IncorrectProgramId);
+            }
+            let (address, bump) = find_quote_address(program_id, request_ai.key, maker_ai.key);
+            if &address != quote_ai.key {
+                return Err(EngineError::InvalidAccountAddress.into());
+            }
+            create_pda_account(
+                program_id,
+                authority_ai,
+                quote_ai,
+                system_program_ai,
+                Quote::LEN,
+                &[
+                    b"quote",
+                    request_ai.key.as_ref(),
+                    maker_ai.key.as_ref(),
+                    &[bump],
+                ],
+            )?;
+            bump
+        };
+
+        let quote = Quote {
+            request: *request_ai.key,
+            maker: *maker_ai.key,
+            bump,
+            price_lots,
+            expiry_ts,
+            side_is_bid: request.side_is_bid,
+            base_lots: request.base_lots,
+        };
+        store_account(quote_ai, &quote)
+    }
+
+    fn process_accept_quote(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let market_ai = next_account_info(account_info_iter)?;
+        let user_ai = next_account_info(account_info_iter)?;
+        let authority_ai = next_account_info(account_info_iter)?;
+        let request_ai = next_account_info(account_info_iter)?;
+        let quote_ai = next_account_info(account_info_iter)?;
+        let maker_ai = next_account_info(account_info_iter)?;
+        let event_queue_ai = next_account_info(account_info_iter)?;
+        let oracle_ai = next_account_info(account_info_iter)?;
+
+        let market = load_account::<Market>(market_ai, program_id)?;
+        if event_queue_ai.key != &market.event_queue || oracle_ai.key != &market.oracle {
+            return Err(EngineError::InvalidAccountAddress.into());
+        }
+        let mut taker = load_user_account(user_ai, program_id, market_ai.key)?;
+        assert_owner_or_delegate(&taker, authority_ai)?;
+
+        let mut request = load_quote_request(request_ai, program_id)?;
+        if &request.user != user_ai.key {
+            return Err(EngineError::InvalidAccountAddress.into());
+        }
+        let quote = load_quote(quote_ai, program_id, request_ai.key)?;
+        if &quote.maker != maker_ai.key {
+            return Err(EngineError::InvalidAccountAddress.into());
+        }
+        if !quote.matches(&request) {
+            return Err(EngineError::InvalidAccountData.into());
+        }
+        let mut maker = load_user_account(maker_ai, program_id, market_ai.key)?;
+
+        let now = Clock::get()?.unix_timestamp;
+        if !request.is_open(now) {
+            return Err(EngineError::QuoteRequestClosed.into());
+        }
+        if !quote.is_live(now) {
+            return Err(EngineError::QuoteExpired.into());
+        }
+        // Both sides must be allowed to trade, each judged on whether the
+        // fill only reduces its own position.
+        for (user, side_is_bid) in [
+            (&taker, request.side_is_bid),
+            (&maker, !request.side_is_bid),
+        ] {
+            if request.base_lots <= reducing_capacity(user, side_is_bid) {
+                market.check_allowed(MarketAction::PlaceReducingOrder)?;
+            } else {
+                market.check_allowed(MarketAction::PlaceOrder)?;
+            }
+        }
+
+        let quote_lots = request
+            .base_lots
+            .checked_mul(quote.price_lots)
+            .ok_or(EngineError::MathError)?;
+        if market.kind == MarketKind::Spot {
+            let (buyer, seller) = if request.side_is_bid {
+                (&taker, &maker)
+            } else {
+                (&maker, &taker)
+            };
+            if buyer.free_quote_lots() < quote_lots || seller.free_base_lots() < request.base_lots {
+                return Err(EngineError::InsufficientFunds.into());
+            }
+        }
+
+        let exposure_before = [taker.max_exposure_lots(), maker.max_exposure_lots()];
+        let mut events = Vec::with_capacity(1);
+        settle_trade(
+            &mut taker,
+            &mut maker,
+            request.side_is_bid,
+            quote.price_lots,
+            request.base_lots,
+            &mut events,
+        );
+        // Both sides of a perp trade are held to the same margin rule as
+        // book orders.
+        if market.kind == MarketKind::Perp {
+            let oracle_price = read_price(oracle_ai, program_id)?.price;
+            for (user, before) in [&taker, &maker].into_iter().zip(exposure_before) {
+                if user.max_exposure_lots() > before
+                    && !market.meets_initial_margin(user, oracle_price)?
+                {
+                    return Err(EngineError::InsufficientFunds.into());
+                }
+            }
+        }
+        taker.last_update_ts = now;
+        request.status = RfqStatus::Filled;
+
+        store_account(user_ai, &taker)?;
+        store_account(maker_ai, &maker)?;
+        store_account(request_ai, &request)?;
+        push_events(program_id, event_queue_ai, &events)
+    }
+
+    fn process_cancel_quote_request(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let market_ai = next_account_info(account_info_iter)?;
+        let user_ai = next_account_info(account_info_iter)?;
+        let authority_ai = next_account_info(account_info_iter)?;
+        let request_ai = next_account_info(account_info_iter)?;
+        let owner_ai = next_account_info(account_info_iter)?;
+
+        let _market = load_account::<Market>(market_ai, program_id)?;
+        let user = load_user_account(user_ai, program_id, market_ai.key)?;
+        assert_owner_or_delegate(&user, authority_ai)?;
+        if owner_ai.key != &user.owner {
+            return Err(EngineError::InvalidAccountAddress.into());
+        }
+
+        let request = load_quote_request(request_ai, program_id)?;
+        if &request.user != user_ai.key {
+            return Err(EngineError::InvalidAccountAddress.into());
+        }
+
+        close_account(request_ai, owner_ai)
+    }
+
+    fn process_cancel_quote(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let market_ai = next_account_info(account_info_iter)?;
+        let maker_ai = next_account_info(account_info_iter)?;
+        let authority_ai = next_account_info(account_info_iter)?;
+        let quote_ai = next_account_info(account_info_iter)?;
+        let owner_ai = next_account_info(account_info_iter)?;
+
+        let _market = load_account::<Market>(market_ai, program_id)?;
+        let maker = load_user_account(maker_ai, program_id, market_ai.key)?;
+        assert_owner_or_delegate(&maker, authority_ai)?;
+        if owner_ai.key != &maker.owner {
+            return Err(EngineError::InvalidAccountAddress.into());
+        }
+
+        let quote = load_account::<Quote>(quote_ai, program_id)?;
+        if &quote.maker != maker_ai.key {
+            return Err(EngineError::InvalidAccountAddress.into());
+        }
+
+        close_account(quote_ai, owner_ai)
+    }
+
+    fn process_settle_pnl(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let market_ai = next_account_info(account_info_iter)?;
+        let a_ai = next_account_info(account_info_iter)?;
+        let b_ai = next_account_info(account_info_iter)?;
+        let oracle_ai = next_account_info(account_info_iter)?;
+
+        let market = load_account::<Market>(market_ai, program_id)?;
+        market.require_kind(MarketKind::Perp)?;
+        if oracle_ai.key != &market.oracle {
+            return Err(EngineError::InvalidAccountAddress.into());
+        }
+        if a_ai.key == b_ai.key {
+            return Err(EngineError::InvalidAccountData.into());
+        }
+        let mut a = load_user_account(a_ai, program_id, market_ai.key)?;
+        let mut b = load_user_account(b_ai, program_id, market_ai.key)?;
+        let oracle_price = read_price(oracle_ai, program_id)?.price;
+        let now = Clock::get()?.unix_timestamp;
+
+        settle_pnl(&mut a, &mut b, oracle_price, now, market.pnl_maturity_secs)?;
+
+        store_account(a_ai, &a)?;
+        store_account(b_ai, &b)
+    }
+
+    fn process_deposit_funds(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        base_amount: u64,
+        quote_amount: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let market_ai = next_account_info(account_info_iter)?;
+        let user_ai = next_account_info(account_info_iter)?;
+        let owner_ai = next_account_info(account_info_iter)?;
+        let owner_base_ai = next_account_info(account_info_iter)?;
+        let owner_quote_ai = next_account_info(account_info_iter)?;
+        let base_vault_ai = next_account_info(account_info_iter)?;
+        let quote_vault_ai = next_account_info(account_info_iter)?;
+        let token_program_ai = next_account_info(account_info_iter)?;
+
+        let market = load_account::<Market>(market_ai, program_id)?;
+        market.check_allowed(MarketAction::Deposit)?;
+        market.require_kind(MarketKind::Spot)?;
+        if base_vault_ai.key != &market.base_vault || quote_vault_ai.key != &market.quote_vault {
+            return Err(EngineError::InvalidAccountAddress.into());
+        }
+        let mut user = load_user_account(user_ai, program_id, market_ai.key)?;
+        assert_owner(&user, owner_ai)?;
+
+        let base_lots = market.native_base_to_lots(base_amount)?;
+        let quote_lots = market.native_quote_to_lots(quote_amount)?;
+        transfer(
+            token_program_ai,
+            owner_base_ai,
+            base_vault_ai,
+            owner_ai,
+            base_amount,
+            &[],
+        )?;
+        transfer(
+            token_program_ai,
+            owner_quote_ai,
+            quote_vault_ai,
+            owner_ai,
+            quote_amount,
+            &[],
+        )?;
+
+        user.base_position = user
+            .base_position
+            .checked_add(base_lots)
+            .ok_or(EngineError::MathError)?;
+        user.quote_position = user
+            .quote_position
+            .checked_add(quote_lots)
+            .ok_or(EngineError::MathError)?;
+        user.last_update_ts = Clock::get()?.unix_timestamp;
+
+        store_account(user_ai, &user)
+    }
+
+    fn process_settle_funds(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let market_ai = next_account_info(account_info_iter)?;
+        let user_ai = next_account_info(account_info_iter)?;
+        let owner_ai = next_account_info(account_info_iter)?;
+        let owner_base_ai = next_account_info(account_info_iter)?;
+        let owner_quote_ai = next_account_info(account_info_iter)?;
+        let base_vault_ai = next_account_info(account_info_iter)?;
+        let quote_vault_ai = next_account_info(account_info_iter)?;
+        let vault_authority_ai = next_account_info(account_info_iter)?;
+        let token_program_ai = next_account_info(account_info_iter)?;
+
+        let market = load_account::<Market>(market_ai, program_id)?;
+        market.check_allowed(MarketAction::Withdraw)?;
+        market.require_kind(MarketKind::Spot)?;
+        if base_vault_ai.key != &market.base_vault || quote_vault_ai.key != &market.quote_vault {
+            return Err(EngineError::InvalidAccountAddress.into());
+        }
+        let mut user = load_user_account(user_ai, program_id, market_ai.key)?;
+        assert_owner(&user, owner_ai)?;
+
+        for (token_ai, mint) in [
+            (owner_base_ai, &market.base_mint),
+            (owner_quote_ai, &market.quote_mint),
+        ] {
+            let token = read_token_account(token_ai)?;
+            if &token.mint != mint || token.owner != user.owner {
+                return Err(EngineError::InvalidAccountData.into());
+            }
+        }
+
+        let base_lots = user.free_base_lots();
+        let quote_lots = user.free_quote_lots();
+        user.base_position -= base_lots;
+        user.quote_position -= quote_lots;
+        user.last_update_ts = Clock::get()?.unix_timestamp;
+        store_account(user_ai, &user)?;
+
+        let seeds: &[&[u8]] = &[b"vault", market_ai.key.as_ref(), &[market.vault_bump]];
+        transfer(
+            token_program_ai,
+            base_vault_ai,
+            owner_base_ai,
+            vault_authority_ai,
+            market.base_lots_to_native(base_lots)?,
+            &[seeds],
+        )?;
+        transfer(
+            token_program_ai,
+            quote_vault_ai,
+            owner_quote_ai,
+            vault_authority_ai,
+            market.quote_lots_to_native(quote_lots)?,
+            &[seeds],
+        )
     }
 }
@@ -1,6 +1,8 @@
-use crate::state::Event;
+use crate::error::EngineError;
+use crate::state::{AccountType, Event, ACCOUNT_HEADER_LEN};
+use crate::utils::{load_account, store_account};
 use borsh::{BorshDeserialize, BorshSerialize};
-use solana_program::program_error::ProgramError;
+use solana_program::{account_info::AccountInfo, program_error::ProgramError, pubkey::Pubkey};
 
 /// Header for an in-account event queue ring buffer.
 #[derive(Clone, Debug, BorshSerialize, BorshDeserialize)]
@@ -10,7 +12,18 @@ pub struct EventQueueHeader {
     pub capacity: u64,
 }
 
+impl AccountType for EventQueueHeader {
+    const DISCRIMINATOR: [u8; 8] = *b"evqueue\0";
+    const VERSION: u8 = 1;
+}
+
 impl EventQueueHeader {
+    /// Serialized length of the header, excluding the account prefix.
+    pub const LEN: usize = 24;
+
+    /// Offset of the first event slot within the account data.
+    pub const DATA_OFFSET: usize = ACCOUNT_HEADER_LEN + Self::LEN;
+
     pub fn init(&mut self, capacity: u64) {
         self.head = 0;
         self.tail = 0;
@@ -18,16 +31,104 @@ impl EventQueueHeader {
     }
 }
 
+/// Loads the event queue header, initializing it if the account is still
+/// zeroed. The capacity is derived from the account size.
+pub fn load_queue_header(
+    program_id: &Pubkey,
+    event_queue_ai: &AccountInfo,
+) -> Result<EventQueueHeader, ProgramError> {
+    if crate::utils::is_zeroed(event_queue_ai) {
+        if event_queue_ai.owner != program_id {
+            return Err(EngineError::InvalidOwner.into());
+        }
+        let data_len = event_queue_ai.data_len();
+        if data_len <= EventQueueHeader::DATA_OFFSET {
+            return Err(EngineError::InvalidAccountData.into());
+        }
+        let capacity = (data_len - EventQueueHeader::DATA_OFFSET) / Event::SLOT_SIZE;
+        let mut header = EventQueueHeader {
+            head: 0,
+            tail: 0,
+            capacity: 0,
+        };
+        header.init(capacity as u64);
+        return Ok(header);
+    }
+
+    load_account(event_queue_ai, program_id)
+}
+
+/// Appends events to the queue account and persists the updated header.
+pub fn push_events(
+    program_id: &Pubkey,
+    event_queue_ai: &AccountInfo,
+    events: &[Event],
+) -> Result<(), ProgramError> {
+    let mut header = load_queue_header(program_id, event_queue_ai)?;
+
+    {
+        let mut buf = event_queue_ai.try_borrow_mut_data()?;
+        let (_, data_region) = buf.split_at_mut(EventQueueHeader::DATA_OFFSET);
+        for event in events.iter() {
+            push_event(&mut header, data_region, event)?;
+        }
+    }
+
+    store_account(event_queue_ai, &header)
+}
+
+/// Reads the event stored at sequence number `seq`.
+pub fn read_event(header: &EventQueueHeader, buf: &[u8], seq: u64) -> Result<Event, ProgramError> {
+    let idx = seq % header.capacity;
+    let offset = (idx as usize) * Event::SLOT_SIZE;
+    Event::deserialize(&mut &buf[offset..offset + Event::SLOT_SIZE])
+        .map_err(|_| EngineError::InvalidAccountData.into())
+}
+
+/// Returns whether any event between head and tail involves the user
+/// account `account_num` of `owner`.
+pub fn has_pending_events_for(
+    program_id: &Pubkey,
+    event_queue_ai: &AccountInfo,
+    owner: &Pubkey,
+    account_num: u32,
+) -> Result<bool, ProgramError> {
+    let header = load_queue_header(program_id, event_queue_ai)?;
+    let buf = event_queue_ai.try_borrow_data()?;
+    let data_region = &buf[EventQueueHeader::DATA_OFFSET..];
+    for seq in header.head..header.tail {
+        if read_event(&header, data_region, seq)?.references(owner, account_num) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Advances the queue head past up to `limit` events.
+pub fn consume_events(
+    program_id: &Pubkey,
+    event_queue_ai: &AccountInfo,
+    limit: u64,
+) -> Result<(), ProgramError> {
+    let mut header = load_queue_header(program_id, event_queue_ai)?;
+    let pending = header.tail.wrapping_sub(header.head);
+    header.head = header.head.wrapping_add(pending.min(limit));
+    store_account(event_queue_ai, &header)
+}
+
 /// Writes an event into the queue at the current tail position.
 pub fn push_event(
     header: &mut EventQueueHeader,
     buf: &mut [u8],
     event: &Event,
 ) -> Result<(), ProgramError> {
+    if header.capacity == 0 {
+        return Err(EngineError::InvalidAccountData.into());
+    }
     let idx = header.tail % header.capacity;
-    let offset = (idx as usize) * Event::serialized_size().unwrap_or(64);
+    let offset = (idx as usize) * Event::SLOT_SIZE;
 
-    let mut slice = &mut buf[offset..offset + Event::serialized_size().unwrap_or(64)];
+    let mut slice = &mut buf[offset..offset + Event::SLOT_SIZE];
     event.serialize(&mut slice)?;
 
     header.tail = header.tail.wrapping_add(1);
@@ -38,16 +139,6 @@ pub fn push_event(
 }
 
 impl Event {
-    fn serialized_size() -> Result<usize, ProgramError> {
-        // Rough upper bound used for calculating offsets.
-        let dummy = Event::FundingUpdate {
-            market: solana_program::pubkey::Pubkey::default(),
-            funding_rate_bps: 0,
-        };
-        let mut data = Vec::with_capacity(128);
-        dummy
-            .serialize(&mut data)
-            .map_err(|_| ProgramError::InvalidAccountData)?;
-        Ok(data.len())
-    }
+    /// Size of a single slot in the queue. Must fit the largest variant.
+    pub const SLOT_SIZE: usize = 128;
 }
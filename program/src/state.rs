@@ -1,16 +1,334 @@
+use crate::error::EngineError;
+use crate::instruction::{OrderParams, PegParams};
 use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::{clock::UnixTimestamp, pubkey::Pubkey};
 
+/// Length of the discriminator and version prefix stored at the start of
+/// every account owned by the program.
+pub const ACCOUNT_HEADER_LEN: usize = 9;
+
+/// Identifies the type and layout version of a program account.
+pub trait AccountType {
+    const DISCRIMINATOR: [u8; 8];
+    const VERSION: u8;
+}
+
 /// Configuration for a single trading market.
 #[derive(Clone, Debug, BorshSerialize, BorshDeserialize)]
 pub struct Market {
     pub admin: Pubkey,
+    pub pending_admin: Pubkey,
     pub base_mint: Pubkey,
     pub quote_mint: Pubkey,
     pub oracle: Pubkey,
+    pub event_queue: Pubkey,
     pub fee_bps: u16,
-    pub is_active: bool,
+    pub init_margin_bps: u16,
+    pub maint_margin_bps: u16,
+    pub status: MarketStatus,
     pub padding: [u8; 5],
+    pub tick_size: i64,
+    pub base_lot_size: u64,
+    pub quote_lot_size: u64,
+    pub min_base_order_size: i64,
+    pub max_price_deviation_bps: u16,
+    pub keeper_fee_lots: i64,
+    pub matching_mode: MatchingMode,
+    /// Length of each auction window in batch auction mode.
+    pub auction_interval_secs: i64,
+    /// End of the current auction window, after which it can be cleared.
+    pub auction_end_ts: UnixTimestamp,
+    /// Delay before settled PnL becomes withdrawable.
+    pub pnl_maturity_secs: i64,
+    pub kind: MarketKind,
+    /// Token vaults of a spot market, owned by the PDA `[b"vault", market]`.
+    pub base_vault: Pubkey,
+    pub quote_vault: Pubkey,
+    pub vault_bump: u8,
+    pub seq_num: u64,
+}
+
+impl AccountType for Market {
+    const DISCRIMINATOR: [u8; 8] = *b"market\0\0";
+    const VERSION: u8 = 12;
+}
+
+impl Market {
+    pub const DEFAULT_INIT_MARGIN_BPS: u16 = 1_000;
+    pub const DEFAULT_MAINT_MARGIN_BPS: u16 = 500;
+    pub const DEFAULT_KEEPER_FEE_LOTS: i64 = 1;
+    pub const DEFAULT_PNL_MATURITY_SECS: i64 = 3_600;
+
+    /// Checks that the configurable parameters are internally consistent.
+    pub fn validate_params(&self) -> Result<(), EngineError> {
+        if self.fee_bps > 10_000
+            || self.init_margin_bps > 10_000
+            || self.maint_margin_bps > self.init_margin_bps
+            || self.tick_size <= 0
+            || self.base_lot_size == 0
+            || self.quote_lot_size == 0
+            || self.min_base_order_size < 1
+            || self.max_price_deviation_bps > 10_000
+            || self.keeper_fee_lots < 0
+            || self.auction_interval_secs < 0
+            || self.pnl_maturity_secs < 0
+            || (self.matching_mode == MatchingMode::BatchAuction && self.auction_interval_secs == 0)
+        {
+            return Err(EngineError::InvalidMarketParams);
+        }
+        Ok(())
+    }
+
+    /// Checks an order's limit price and size against the tick size and
+    /// minimum order size.
+    pub fn validate_order(&self, price_lots: i64, base_lots: i64) -> Result<(), EngineError> {
+        if price_lots <= 0
+            || price_lots % self.tick_size != 0
+            || base_lots < self.min_base_order_size
+        {
+            return Err(EngineError::InvalidOrderParams);
+        }
+        Ok(())
+    }
+
+    /// Checks the peg of an oracle-pegged order and its size. The offset
+    /// and limit must sit on the tick grid; the oracle-derived price need not.
+    pub fn validate_pegged_order(
+        &self,
+        peg: &PegParams,
+        base_lots: i64,
+    ) -> Result<(), EngineError> {
+        if peg.offset_lots % self.tick_size != 0
+            || peg
+                .limit_lots
+                .is_some_and(|limit| limit <= 0 || limit % self.tick_size != 0)
+            || base_lots < self.min_base_order_size
+        {
+            return Err(EngineError::InvalidOrderParams);
+        }
+        Ok(())
+    }
+
+    /// Checks `price_lots` against the band of `max_price_deviation_bps`
+    /// around `oracle_price`. Out-of-band orders are rejected, except that an
+    /// IOC order priced too aggressively is clamped to the band edge.
+    /// Returns the price to use. A deviation of zero disables the band.
+    pub fn apply_price_band(
+        &self,
+        price_lots: i64,
+        side_is_bid: bool,
+        order_type: OrderType,
+        oracle_price: i64,
+    ) -> Result<i64, EngineError> {
+        if self.max_price_deviation_bps == 0 {
+            return Ok(price_lots);
+        }
+        if oracle_price <= 0 {
+            return Err(EngineError::PriceOutsideBand);
+        }
+
+        let deviation = (oracle_price as i128) * (self.max_price_deviation_bps as i128) / 10_000;
+        let lower = (oracle_price as i128 - deviation) as i64;
+        let upper = (oracle_price as i128 + deviation) as i64;
+
+        let (too_aggressive, too_passive) = if side_is_bid {
+            (price_lots > upper, price_lots < lower)
+        } else {
+            (price_lots < lower, price_lots > upper)
+        };
+        if too_passive || (too_aggressive && order_type != OrderType::ImmediateOrCancel) {
+            return Err(EngineError::PriceOutsideBand);
+        }
+        if !too_aggressive {
+            return Ok(price_lots);
+        }
+
+        // Round the band edge onto the tick grid, staying inside the band.
+        let clamped = if side_is_bid {
+            upper - upper.rem_euclid(self.tick_size)
+        } else {
+            lower + (self.tick_size - lower.rem_euclid(self.tick_size)) % self.tick_size
+        };
+        if clamped <= 0 || clamped < lower || clamped > upper {
+            return Err(EngineError::PriceOutsideBand);
+        }
+        Ok(clamped)
+    }
+
+    /// Equity a perp account needs to hold `base_lots` of position at
+    /// `oracle_price` under the initial margin, rounded up.
+    pub fn initial_margin(&self, base_lots: i64, oracle_price: i64) -> Result<i64, EngineError> {
+        let notional = base_lots.unsigned_abs() as i128 * oracle_price as i128;
+        let margin = (notional * self.init_margin_bps as i128 + 9_999) / 10_000;
+        i64::try_from(margin).map_err(|_| EngineError::MathError)
+    }
+
+    /// Returns whether `user`'s equity covers the initial margin on the
+    /// largest position its resting orders could still leave it with.
+    pub fn meets_initial_margin(
+        &self,
+        user: &UserAccount,
+        oracle_price: i64,
+    ) -> Result<bool, EngineError> {
+        let required = self.initial_margin(user.max_exposure_lots(), oracle_price)?;
+        Ok(user.equity(oracle_price)? >= required)
+    }
+
+    /// Converts a native quote token amount into quote lots. The amount
+    /// must be a whole number of lots.
+    pub fn native_quote_to_lots(&self, amount: u64) -> Result<i64, EngineError> {
+        native_to_lots(amount, self.quote_lot_size)
+    }
+
+    /// Converts quote lots into a native quote token amount.
+    pub fn quote_lots_to_native(&self, lots: i64) -> Result<u64, EngineError> {
+        lots_to_native(lots, self.quote_lot_size)
+    }
+
+    /// Converts a native base token amount into base lots. The amount must
+    /// be a whole number of lots.
+    pub fn native_base_to_lots(&self, amount: u64) -> Result<i64, EngineError> {
+        native_to_lots(amount, self.base_lot_size)
+    }
+
+    /// Converts base lots into a native base token amount.
+    pub fn base_lots_to_native(&self, lots: i64) -> Result<u64, EngineError> {
+        lots_to_native(lots, self.base_lot_size)
+    }
+
+    /// Fails with [EngineError::MarketInactive] if the current status does
+    /// not permit `action`.
+    pub fn check_allowed(&self, action: MarketAction) -> Result<(), EngineError> {
+        if !self.status.allows(action) {
+            return Err(EngineError::MarketInactive);
+        }
+        Ok(())
+    }
+
+    /// Fails with [EngineError::InvalidMarketKind] unless the market settles
+    /// as `kind`.
+    pub fn require_kind(&self, kind: MarketKind) -> Result<(), EngineError> {
+        if self.kind != kind {
+            return Err(EngineError::InvalidMarketKind);
+        }
+        Ok(())
+    }
+}
+
+fn native_to_lots(amount: u64, lot_size: u64) -> Result<i64, EngineError> {
+    if lot_size == 0 || !amount.is_multiple_of(lot_size) {
+        return Err(EngineError::InvalidLotAmount);
+    }
+    i64::try_from(amount / lot_size).map_err(|_| EngineError::MathError)
+}
+
+fn lots_to_native(lots: i64, lot_size: u64) -> Result<u64, EngineError> {
+    u64::try_from(lots)
+        .ok()
+        .and_then(|lots| lots.checked_mul(lot_size))
+        .ok_or(EngineError::MathError)
+}
+
+/// Settlement model of a market.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub enum MarketKind {
+    /// Signed base and quote positions against deposited quote collateral.
+    #[default]
+    Perp,
+    /// Users hold non-negative base and quote token balances in the market
+    /// vaults; resting orders lock the funds they would pay with.
+    Spot,
+}
+
+/// How incoming orders are matched on a market.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub enum MatchingMode {
+    /// Orders match against the book as they arrive.
+    #[default]
+    Continuous,
+    /// Orders rest until the auction window closes and then clear together
+    /// at a single uniform price.
+    BatchAuction,
+}
+
+/// How the unfilled part of an order is handled.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub enum OrderType {
+    /// Rest any remaining quantity on the book.
+    #[default]
+    Limit,
+    /// Match what is possible immediately and drop the remainder.
+    ImmediateOrCancel,
+}
+
+/// Order side.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub enum Side {
+    Bid,
+    Ask,
+}
+
+impl Side {
+    pub fn is_bid(self) -> bool {
+        self == Side::Bid
+    }
+}
+
+/// Lifecycle state of a market.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub enum MarketStatus {
+    /// Normal trading.
+    Active,
+    /// Only orders that reduce an existing position may be placed.
+    ReduceOnly,
+    /// Trading and liquidations are halted; funds may still move.
+    Paused,
+    /// Winding down: only cancels and withdrawals are permitted.
+    Settling,
+    /// Terminal state; users may only cancel and withdraw.
+    Closed,
+    /// Orders rest without matching until `OpenMarket` uncrosses them in an
+    /// opening auction and activates the market.
+    PreOpen,
+}
+
+/// Operations whose availability depends on [MarketStatus].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MarketAction {
+    CreateUserAccount,
+    Deposit,
+    Withdraw,
+    PlaceOrder,
+    PlaceReducingOrder,
+    CancelOrder,
+    Liquidate,
+}
+
+impl MarketStatus {
+    /// Returns whether `action` may be performed in this status.
+    pub fn allows(self, action: MarketAction) -> bool {
+        use MarketAction::*;
+        match self {
+            MarketStatus::Active => true,
+            MarketStatus::PreOpen => !matches!(action, Liquidate),
+            MarketStatus::ReduceOnly => !matches!(action, PlaceOrder),
+            MarketStatus::Paused => {
+                matches!(action, CreateUserAccount | Deposit | Withdraw | CancelOrder)
+            }
+            MarketStatus::Settling | MarketStatus::Closed => {
+                matches!(action, Withdraw | CancelOrder)
+            }
+        }
+    }
+
+    /// Returns whether the market may move from this status to `next`.
+    /// `Closed` is terminal, and `PreOpen` only becomes `Active` through the
+    /// opening auction.
+    pub fn can_transition_to(self, next: MarketStatus) -> bool {
+        self != MarketStatus::Closed
+            && self != next
+            && !(self == MarketStatus::PreOpen && next == MarketStatus::Active)
+    }
 }
 
 /// User account tracking balances and open orders.
@@ -18,10 +336,120 @@ pub struct Market {
 pub struct UserAccount {
     pub owner: Pubkey,
     pub market: Pubkey,
+    pub delegate: Pubkey,
+    pub account_num: u32,
+    pub bump: u8,
     pub base_position: i64,
     pub quote_position: i64,
     pub last_update_ts: UnixTimestamp,
-    pub open_orders: [Order; 8],
+    /// Quote lots deposited minus withdrawn from deposits, so that
+    /// `quote_position` can be split into collateral and trading PnL.
+    pub net_deposits_lots: i64,
+    /// Settled PnL that has matured and may be withdrawn.
+    pub settled_pnl_lots: i64,
+    /// Settled PnL still waiting for the market's maturity delay.
+    pub pending_pnl_lots: i64,
+    /// Amount-weighted settlement time of `pending_pnl_lots`.
+    pub pending_pnl_ts: UnixTimestamp,
+    /// Base lots reserved by resting orders, see [Order::reserved_lots].
+    pub locked_base_lots: i64,
+    /// Quote lots reserved by resting orders, see [Order::reserved_lots].
+    pub locked_quote_lots: i64,
+    pub open_orders: Vec<Order>,
+}
+
+impl AccountType for UserAccount {
+    const DISCRIMINATOR: [u8; 8] = *b"useracct";
+    const VERSION: u8 = 10;
+}
+
+impl UserAccount {
+    /// Upper bound on open-order slots, keeping resizes within the
+    /// per-instruction realloc limit.
+    pub const MAX_OPEN_ORDERS: usize = 128;
+
+    /// Account size including the discriminator prefix for a user account
+    /// with `max_orders` open-order slots.
+    pub const fn space(max_orders: usize) -> usize {
+        // owner, market, delegate, account_num, bump, nine i64 balances and
+        // timestamps, then the order vector length prefix.
+        ACCOUNT_HEADER_LEN + 32 + 32 + 32 + 4 + 1 + 8 * 9 + 4 + Order::LEN * max_orders
+    }
+
+    /// Recomputes the locked balances from the resting orders. Must run
+    /// whenever orders rest, fill or are cancelled before the account is
+    /// stored.
+    pub fn update_locked_lots(&mut self, kind: MarketKind) {
+        let (base, quote) = self
+            .open_orders
+            .iter()
+            .map(|order| order.reserved_lots(kind))
+            .fold((0i64, 0i64), |(base, quote), (b, q)| {
+                (base.saturating_add(b), quote.saturating_add(q))
+            });
+        self.locked_base_lots = base;
+        self.locked_quote_lots = quote;
+    }
+
+    /// Base lots not reserved by resting orders.
+    pub fn free_base_lots(&self) -> i64 {
+        self.base_position - self.locked_base_lots
+    }
+
+    /// Quote lots not reserved by resting orders.
+    pub fn free_quote_lots(&self) -> i64 {
+        self.quote_position - self.locked_quote_lots
+    }
+
+    /// Unrealized PnL at `oracle_price`: the value of the position and quote
+    /// balance beyond what was deposited.
+    pub fn unrealized_pnl(&self, oracle_price: i64) -> Result<i64, EngineError> {
+        self.base_position
+            .checked_mul(oracle_price)
+            .and_then(|value| value.checked_add(self.quote_position))
+            .and_then(|equity| equity.checked_sub(self.net_deposits_lots))
+            .ok_or(EngineError::MathError)
+    }
+
+    /// Perp equity at `oracle_price`: net deposits plus unrealized PnL and
+    /// settled PnL, matured or not.
+    pub fn equity(&self, oracle_price: i64) -> Result<i64, EngineError> {
+        self.unrealized_pnl(oracle_price)?
+            .checked_add(self.net_deposits_lots)
+            .and_then(|equity| equity.checked_add(self.settled_pnl_lots))
+            .and_then(|equity| equity.checked_add(self.pending_pnl_lots))
+            .ok_or(EngineError::MathError)
+    }
+
+    /// Largest absolute position the account could reach if every order
+    /// resting on one side filled. Independent of price, so pegged orders
+    /// count the same as fixed ones.
+    pub fn max_exposure_lots(&self) -> i64 {
+        let (bids, asks) = self
+            .open_orders
+            .iter()
+            .filter(|order| order.is_active)
+            .fold((0i64, 0i64), |(bids, asks), order| {
+                let size = order.total_base_lots();
+                if order.side_is_bid {
+                    (bids.saturating_add(size), asks)
+                } else {
+                    (bids, asks.saturating_add(size))
+                }
+            });
+        let long = self.base_position.saturating_add(bids);
+        let short = self.base_position.saturating_sub(asks);
+        long.saturating_abs().max(short.saturating_abs())
+    }
+
+    /// Moves pending settled PnL into the withdrawable balance once
+    /// `maturity_secs` have passed since `pending_pnl_ts`.
+    pub fn mature_pnl(&mut self, now: UnixTimestamp, maturity_secs: i64) {
+        if self.pending_pnl_lots != 0 && now >= self.pending_pnl_ts.saturating_add(maturity_secs) {
+            self.settled_pnl_lots += self.pending_pnl_lots;
+            self.pending_pnl_lots = 0;
+        }
+    }
 }
 
 /// Compact in-memory representation of a single order.
@@ -32,6 +460,307 @@ pub struct Order {
     pub base_lots: i64,
     pub side_is_bid: bool,
     pub is_active: bool,
+    /// Unix timestamp after which the order is no longer matchable, or zero
+    /// for orders that never expire.
+    pub expiry_ts: UnixTimestamp,
+    /// Whether fills are capped so the order can only shrink the owner's
+    /// position.
+    pub reduce_only: bool,
+    /// Whether the order trades at `oracle + peg_offset_lots` rather than at
+    /// `price_lots`, which then only records the price at placement.
+    pub is_pegged: bool,
+    pub peg_offset_lots: i64,
+    /// Worst price a pegged order may trade at, or zero for no limit.
+    pub peg_limit_lots: i64,
+    /// Visible size an iceberg order is refilled to, or zero for orders
+    /// showing their full size.
+    pub display_base_lots: i64,
+    /// Iceberg reserve not yet shown in `base_lots`.
+    pub hidden_base_lots: i64,
+}
+
+impl Event {
+    /// Returns whether the event involves the user account `account_num`
+    /// of `owner`.
+    pub fn references(&self, owner: &Pubkey, account_num: u32) -> bool {
+        let is = |key: &Pubkey, num: &u32| key == owner && *num == account_num;
+        match self {
+            Event::Trade {
+                maker,
+                maker_account_num,
+                taker,
+                taker_account_num,
+                ..
+            } => is(maker, maker_account_num) || is(taker, taker_account_num),
+            Event::Out {
+                owner: key,
+                account_num: num,
+                ..
+            }
+            | Event::Refill {
+                owner: key,
+                account_num: num,
+                ..
+            } => is(key, num),
+            Event::FundingUpdate { .. } | Event::MarketParamsUpdated { .. } => false,
+        }
+    }
+}
+
+impl Order {
+    /// Serialized size of a single order slot.
+    pub const LEN: usize = 16 + 8 + 8 + 1 + 1 + 8 + 1 + 1 + 8 + 8 + 8 + 8;
+
+    /// Returns the visible plus hidden size of the order.
+    pub fn total_base_lots(&self) -> i64 {
+        self.base_lots + self.hidden_base_lots
+    }
+
+    /// Base and quote lots the order reserves while it rests on a spot
+    /// market. Bids reserve their remaining size at their limit price in
+    /// quote and asks reserve the base they sell. Perp orders reserve
+    /// nothing; they are covered by [Market::meets_initial_margin].
+    pub fn reserved_lots(&self, kind: MarketKind) -> (i64, i64) {
+        if !self.is_active || kind == MarketKind::Perp {
+            return (0, 0);
+        }
+        let size = self.total_base_lots();
+        if self.side_is_bid {
+            (0, size.saturating_mul(self.price_lots))
+        } else {
+            (size, 0)
+        }
+    }
+
+    /// Returns whether the order has an expiry that has passed at `now`.
+    pub fn is_expired(&self, now: UnixTimestamp) -> bool {
+        self.expiry_ts != 0 && now >= self.expiry_ts
+    }
+
+    /// Returns the price the order trades at for `oracle_price`, or `None`
+    /// if a pegged order currently has no positive price.
+    pub fn effective_price(&self, oracle_price: i64) -> Option<i64> {
+        if !self.is_pegged {
+            return Some(self.price_lots);
+        }
+        Self::pegged_price(
+            self.side_is_bid,
+            self.peg_offset_lots,
+            self.peg_limit_lots,
+            oracle_price,
+        )
+    }
+
+    /// Computes `oracle_price + offset_lots` capped by `limit_lots` (zero for
+    /// none): bids never pay more and asks never receive less than the limit.
+    pub fn pegged_price(
+        side_is_bid: bool,
+        offset_lots: i64,
+        limit_lots: i64,
+        oracle_price: i64,
+    ) -> Option<i64> {
+        let mut price = oracle_price.checked_add(offset_lots)?;
+        if limit_lots != 0 {
+            price = if side_is_bid {
+                price.min(limit_lots)
+            } else {
+                price.max(limit_lots)
+            };
+        }
+        (price > 0).then_some(price)
+    }
+}
+
+/// Oracle price condition that arms a trigger order.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub enum TriggerDirection {
+    /// Fires once the oracle price is at or above the trigger price.
+    Above,
+    /// Fires once the oracle price is at or below the trigger price.
+    Below,
+}
+
+/// A stop-loss or take-profit order waiting for its oracle condition.
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize, PartialEq)]
+pub struct TriggerOrder {
+    pub id: u64,
+    pub trigger_price: i64,
+    pub direction: TriggerDirection,
+    pub params: OrderParams,
+}
+
+impl TriggerOrder {
+    /// Upper bound on the serialized size of a trigger order.
+    pub const MAX_LEN: usize = 8 + 8 + 1 + OrderParams::MAX_LEN;
+
+    /// Returns whether the trigger condition holds at `oracle_price`.
+    pub fn is_triggered(&self, oracle_price: i64) -> bool {
+        match self.direction {
+            TriggerDirection::Above => oracle_price >= self.trigger_price,
+            TriggerDirection::Below => oracle_price <= self.trigger_price,
+        }
+    }
+}
+
+/// Per-user store of pending trigger orders, kept at the PDA derived from
+/// `[b"triggers", user]`.
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize)]
+pub struct TriggerOrders {
+    pub user: Pubkey,
+    pub bump: u8,
+    pub next_id: u64,
+    pub orders: Vec<TriggerOrder>,
+}
+
+impl AccountType for TriggerOrders {
+    const DISCRIMINATOR: [u8; 8] = *b"triggers";
+    const VERSION: u8 = 3;
+}
+
+impl TriggerOrders {
+    pub const MAX_ORDERS: usize = 16;
+
+    /// Account size including the discriminator prefix.
+    pub const LEN: usize =
+        ACCOUNT_HEADER_LEN + 32 + 1 + 8 + 4 + TriggerOrder::MAX_LEN * Self::MAX_ORDERS;
+}
+
+/// Lifecycle of a TWAP order.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub enum TwapStatus {
+    Active,
+    /// Every slice has run or the full size was filled.
+    Completed,
+    Cancelled,
+}
+
+/// Parent order executed in IOC slices by a crank, kept at the PDA derived
+/// from `[b"twap", user, nonce]`.
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize)]
+pub struct TwapOrder {
+    pub user: Pubkey,
+    pub nonce: u64,
+    pub bump: u8,
+    pub status: TwapStatus,
+    pub side_is_bid: bool,
+    pub limit_price_lots: i64,
+    pub total_base_lots: i64,
+    pub filled_base_lots: i64,
+    pub num_slices: u16,
+    pub executed_slices: u16,
+    pub interval_secs: i64,
+    pub next_slice_ts: UnixTimestamp,
+}
+
+impl AccountType for TwapOrder {
+    const DISCRIMINATOR: [u8; 8] = *b"twap\0\0\0\0";
+    const VERSION: u8 = 1;
+}
+
+impl TwapOrder {
+    /// Account size including the discriminator prefix.
+    pub const LEN: usize = ACCOUNT_HEADER_LEN + 32 + 8 + 1 + 1 + 1 + 8 + 8 + 8 + 2 + 2 + 8 + 8;
+
+    /// Returns whether the next slice may execute at `now`.
+    pub fn is_due(&self, now: UnixTimestamp) -> bool {
+        self.status == TwapStatus::Active && now >= self.next_slice_ts
+    }
+
+    /// Size of the next slice: the unfilled size spread evenly over the
+    /// remaining slices, so shortfalls from earlier slices are caught up,
+    /// but never below `min_base_lots` or above the unfilled size.
+    pub fn next_slice_base_lots(&self, min_base_lots: i64) -> i64 {
+        let remaining = self.total_base_lots - self.filled_base_lots;
+        let slices_left = (self.num_slices - self.executed_slices).max(1) as i64;
+        ((remaining + slices_left - 1) / slices_left)
+            .max(min_base_lots)
+            .min(remaining)
+    }
+
+    /// Records the outcome of a slice and schedules the next one. The order
+    /// completes once every slice has run or less than `min_base_lots`
+    /// remains unfilled.
+    pub fn record_slice(&mut self, filled_base_lots: i64, min_base_lots: i64) {
+        self.filled_base_lots += filled_base_lots;
+        self.executed_slices += 1;
+        self.next_slice_ts += self.interval_secs;
+        if self.executed_slices >= self.num_slices
+            || self.total_base_lots - self.filled_base_lots < min_base_lots.max(1)
+        {
+            self.status = TwapStatus::Completed;
+        }
+    }
+}
+
+/// Lifecycle of a quote request.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub enum RfqStatus {
+    Open,
+    Filled,
+}
+
+/// Block trade request posted by a taker, kept at the PDA derived from
+/// `[b"rfq", user, nonce]`.
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize)]
+pub struct QuoteRequest {
+    pub user: Pubkey,
+    pub nonce: u64,
+    pub bump: u8,
+    pub status: RfqStatus,
+    pub side_is_bid: bool,
+    pub base_lots: i64,
+    pub expiry_ts: UnixTimestamp,
+}
+
+impl AccountType for QuoteRequest {
+    const DISCRIMINATOR: [u8; 8] = *b"rfq\0\0\0\0\0";
+    const VERSION: u8 = 1;
+}
+
+impl QuoteRequest {
+    /// Account size including the discriminator prefix.
+    pub const LEN: usize = ACCOUNT_HEADER_LEN + 32 + 8 + 1 + 1 + 1 + 8 + 8;
+
+    /// Returns whether the request can still be quoted and filled at `now`.
+    pub fn is_open(&self, now: UnixTimestamp) -> bool {
+        self.status == RfqStatus::Open && now < self.expiry_ts
+    }
+}
+
+/// A maker's price for a quote request, kept at the PDA derived from
+/// `[b"quote", request, maker]` and posted by the maker's signer.
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize)]
+pub struct Quote {
+    pub request: Pubkey,
+    pub maker: Pubkey,
+    pub bump: u8,
+    pub price_lots: i64,
+    pub expiry_ts: UnixTimestamp,
+    /// Side and size of the request when the quote was posted. A closed
+    /// request's address can be reused, so the quote only fills the terms
+    /// it was priced for.
+    pub side_is_bid: bool,
+    pub base_lots: i64,
+}
+
+impl AccountType for Quote {
+    const DISCRIMINATOR: [u8; 8] = *b"quote\0\0\0";
+    const VERSION: u8 = 2;
+}
+
+impl Quote {
+    /// Account size including the discriminator prefix.
+    pub const LEN: usize = ACCOUNT_HEADER_LEN + 32 + 32 + 1 + 8 + 8 + 1 + 8;
+
+    /// Returns whether the quote can still be accepted at `now`.
+    pub fn is_live(&self, now: UnixTimestamp) -> bool {
+        now < self.expiry_ts
+    }
+
+    /// Returns whether the quote was posted for `request`'s current terms.
+    pub fn matches(&self, request: &QuoteRequest) -> bool {
+        self.side_is_bid == request.side_is_bid && self.base_lots == request.base_lots
+    }
 }
 
 /// Oracle price record stored on-chain.
@@ -42,12 +771,19 @@ pub struct OraclePrice {
     pub last_updated_slot: u64,
 }
 
+impl AccountType for OraclePrice {
+    const DISCRIMINATOR: [u8; 8] = *b"oracle\0\0";
+    const VERSION: u8 = 1;
+}
+
 /// Event types pushed into a ring buffer queue.
 #[derive(Clone, Debug, BorshSerialize, BorshDeserialize)]
 pub enum Event {
     Trade {
         maker: Pubkey,
+        maker_account_num: u32,
         taker: Pubkey,
+        taker_account_num: u32,
         price_lots: i64,
         base_lots: i64,
     },
@@ -55,4 +791,29 @@ pub enum Event {
         market: Pubkey,
         funding_rate_bps: i64,
     },
+    Out {
+        owner: Pubkey,
+        account_num: u32,
+        order_id: u128,
+        side_is_bid: bool,
+        base_lots: i64,
+    },
+    MarketParamsUpdated {
+        market: Pubkey,
+        fee_bps: u16,
+        oracle: Pubkey,
+        init_margin_bps: u16,
+        maint_margin_bps: u16,
+        tick_size: i64,
+    },
+    /// An iceberg order showed `base_lots` more from its reserve and was
+    /// requeued under a new id.
+    Refill {
+        owner: Pubkey,
+        account_num: u32,
+        old_order_id: u128,
+        order_id: u128,
+        side_is_bid: bool,
+        base_lots: i64,
+    },
 }
@@ -0,0 +1,90 @@
+use crate::error::EngineError;
+use crate::ids::token_program_id;
+use solana_program::{
+    account_info::AccountInfo,
+    entrypoint::ProgramResult,
+    instruction::{AccountMeta, Instruction},
+    program::invoke_signed,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+};
+
+/// Packed size of an SPL Token account.
+pub const TOKEN_ACCOUNT_LEN: usize = 165;
+
+/// SPL Token instruction tag for `Transfer`.
+const TRANSFER_TAG: u8 = 3;
+
+/// Fields of an SPL Token account used by the spot settlement path.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TokenAccount {
+    pub mint: Pubkey,
+    pub owner: Pubkey,
+    pub amount: u64,
+}
+
+/// Decodes the mint, owner and amount from packed SPL Token account data.
+pub fn unpack_token_account(data: &[u8]) -> Result<TokenAccount, ProgramError> {
+    if data.len() != TOKEN_ACCOUNT_LEN {
+        return Err(EngineError::InvalidAccountData.into());
+    }
+    let pubkey_at = |offset: usize| {
+        Pubkey::try_from(&data[offset..offset + 32]).map_err(|_| EngineError::InvalidAccountData)
+    };
+    let mut amount = [0u8; 8];
+    amount.copy_from_slice(&data[64..72]);
+    Ok(TokenAccount {
+        mint: pubkey_at(0)?,
+        owner: pubkey_at(32)?,
+        amount: u64::from_le_bytes(amount),
+    })
+}
+
+/// Reads an account owned by the SPL Token program.
+pub fn read_token_account(account: &AccountInfo) -> Result<TokenAccount, ProgramError> {
+    if account.owner != &token_program_id() {
+        return Err(EngineError::InvalidOwner.into());
+    }
+    unpack_token_account(&account.try_borrow_data()?)
+}
+
+/// Invokes SPL Token `Transfer`, signing with `signer_seeds` when the
+/// authority is a program address.
+pub fn transfer<'a>(
+    token_program_ai: &AccountInfo<'a>,
+    source_ai: &AccountInfo<'a>,
+    destination_ai: &AccountInfo<'a>,
+    authority_ai: &AccountInfo<'a>,
+    amount: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    if token_program_ai.key != &token_program_id() {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    if amount == 0 {
+        return Ok(());
+    }
+
+    let mut data = Vec::with_capacity(9);
+    data.push(TRANSFER_TAG);
+    data.extend_from_slice(&amount.to_le_bytes());
+    let ix = Instruction {
+        program_id: token_program_id(),
+        accounts: vec![
+            AccountMeta::new(*source_ai.key, false),
+            AccountMeta::new(*destination_ai.key, false),
+            AccountMeta::new_readonly(*authority_ai.key, true),
+        ],
+        data,
+    };
+    invoke_signed(
+        &ix,
+        &[
+            source_ai.clone(),
+            destination_ai.clone(),
+            authority_ai.clone(),
+            token_program_ai.clone(),
+        ],
+        signer_seeds,
+    )
+}
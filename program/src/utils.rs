@@ -1,6 +1,18 @@
 use crate::error::EngineError;
+use crate::state::{
+    AccountType, Market, Quote, QuoteRequest, TriggerOrders, TwapOrder, UserAccount,
+    ACCOUNT_HEADER_LEN,
+};
+use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::{
-    account_info::AccountInfo, program_error::ProgramError, program_pack::Pack, rent::Rent,
+    account_info::AccountInfo,
+    entrypoint::ProgramResult,
+    msg,
+    program::{invoke, invoke_signed},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    rent::Rent,
+    system_instruction,
     sysvar::Sysvar,
 };
 
@@ -17,3 +29,300 @@ pub fn assert_rent_exempt(account: &AccountInfo) -> Result<(), ProgramError> {
 pub fn is_zeroed(account: &AccountInfo) -> bool {
     account.data.borrow().iter().all(|b| *b == 0)
 }
+
+/// Checks that `data` starts with the discriminator and version of `T`.
+pub fn check_account_header<T: AccountType>(data: &[u8]) -> Result<(), ProgramError> {
+    if data.len() < ACCOUNT_HEADER_LEN || data[..8] != T::DISCRIMINATOR {
+        return Err(EngineError::InvalidAccountType.into());
+    }
+    if data[8] != T::VERSION {
+        return Err(EngineError::InvalidAccountVersion.into());
+    }
+    Ok(())
+}
+
+/// Deserializes `T` from account data after validating its header.
+pub fn load_from_data<T: AccountType + BorshDeserialize>(data: &[u8]) -> Result<T, ProgramError> {
+    check_account_header::<T>(data)?;
+    T::deserialize(&mut &data[ACCOUNT_HEADER_LEN..]).map_err(|_| {
+        msg!("failed to deserialize account");
+        EngineError::InvalidAccountData.into()
+    })
+}
+
+/// Loads a program-owned account of type `T`, checking owner, discriminator
+/// and layout version before deserializing.
+pub fn load_account<T: AccountType + BorshDeserialize>(
+    account: &AccountInfo,
+    program_id: &Pubkey,
+) -> Result<T, ProgramError> {
+    if account.owner != program_id {
+        return Err(EngineError::InvalidOwner.into());
+    }
+    load_from_data(&account.try_borrow_data()?)
+}
+
+/// Serializes `value` into `data` prefixed by its discriminator and version.
+pub fn store_to_data<T: AccountType + BorshSerialize>(
+    data: &mut [u8],
+    value: &T,
+) -> Result<(), ProgramError> {
+    if data.len() < ACCOUNT_HEADER_LEN {
+        return Err(EngineError::InvalidAccountData.into());
+    }
+    data[..8].copy_from_slice(&T::DISCRIMINATOR);
+    data[8] = T::VERSION;
+    value
+        .serialize(&mut &mut data[ACCOUNT_HEADER_LEN..])
+        .map_err(|_| EngineError::InvalidAccountData.into())
+}
+
+/// Writes `value` back into the account with its discriminator and version.
+pub fn store_account<T: AccountType + BorshSerialize>(
+    account: &AccountInfo,
+    value: &T,
+) -> ProgramResult {
+    store_to_data(&mut account.try_borrow_mut_data()?, value)
+}
+
+/// Derives the address of a user account for `owner` on `market`.
+pub fn find_user_account_address(
+    program_id: &Pubkey,
+    market: &Pubkey,
+    owner: &Pubkey,
+    account_num: u32,
+) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[market.as_ref(), owner.as_ref(), &account_num.to_le_bytes()],
+        program_id,
+    )
+}
+
+/// Checks that `user_ai` sits at the PDA recorded in `user` for `market`.
+pub fn assert_user_account_address(
+    program_id: &Pubkey,
+    user_ai: &AccountInfo,
+    market: &Pubkey,
+    user: &UserAccount,
+) -> ProgramResult {
+    if &user.market != market {
+        return Err(EngineError::InvalidAccountAddress.into());
+    }
+    let expected = Pubkey::create_program_address(
+        &[
+            user.market.as_ref(),
+            user.owner.as_ref(),
+            &user.account_num.to_le_bytes(),
+            &[user.bump],
+        ],
+        program_id,
+    )
+    .map_err(|_| EngineError::InvalidAccountAddress)?;
+    if &expected != user_ai.key {
+        return Err(EngineError::InvalidAccountAddress.into());
+    }
+    Ok(())
+}
+
+/// Loads a user account and verifies it is the PDA belonging to `market`.
+pub fn load_user_account(
+    user_ai: &AccountInfo,
+    program_id: &Pubkey,
+    market: &Pubkey,
+) -> Result<UserAccount, ProgramError> {
+    let user = load_account::<UserAccount>(user_ai, program_id)?;
+    assert_user_account_address(program_id, user_ai, market, &user)?;
+    Ok(user)
+}
+
+/// Derives the address of the trigger order store for a user account.
+pub fn find_trigger_orders_address(program_id: &Pubkey, user: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"triggers", user.as_ref()], program_id)
+}
+
+/// Loads the trigger order store of `user` and verifies its address.
+pub fn load_trigger_orders(
+    triggers_ai: &AccountInfo,
+    program_id: &Pubkey,
+    user: &Pubkey,
+) -> Result<TriggerOrders, ProgramError> {
+    let triggers = load_account::<TriggerOrders>(triggers_ai, program_id)?;
+    let expected =
+        Pubkey::create_program_address(&[b"triggers", user.as_ref(), &[triggers.bump]], program_id)
+            .map_err(|_| EngineError::InvalidAccountAddress)?;
+    if &triggers.user != user || &expected != triggers_ai.key {
+        return Err(EngineError::InvalidAccountAddress.into());
+    }
+    Ok(triggers)
+}
+
+/// Derives the authority that owns the token vaults of a spot market.
+pub fn find_vault_authority(program_id: &Pubkey, market: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"vault", market.as_ref()], program_id)
+}
+
+/// Derives the address of a TWAP order of a user account.
+pub fn find_twap_order_address(program_id: &Pubkey, user: &Pubkey, nonce: u64) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"twap", user.as_ref(), &nonce.to_le_bytes()], program_id)
+}
+
+/// Loads a TWAP order of `user` and verifies its address.
+pub fn load_twap_order(
+    twap_ai: &AccountInfo,
+    program_id: &Pubkey,
+    user: &Pubkey,
+) -> Result<TwapOrder, ProgramError> {
+    let twap = load_account::<TwapOrder>(twap_ai, program_id)?;
+    let expected = Pubkey::create_program_address(
+        &[
+            b"twap",
+            user.as_ref(),
+            &twap.nonce.to_le_bytes(),
+            &[twap.bump],
+        ],
+        program_id,
+    )
+    .map_err(|_| EngineError::InvalidAccountAddress)?;
+    if &twap.user != user || &expected != twap_ai.key {
+        return Err(EngineError::InvalidAccountAddress.into());
+    }
+    Ok(twap)
+}
+
+/// Derives the address of a quote request of a user account.
+pub fn find_quote_request_address(program_id: &Pubkey, user: &Pubkey, nonce: u64) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"rfq", user.as_ref(), &nonce.to_le_bytes()], program_id)
+}
+
+/// Loads a quote request and verifies its address.
+pub fn load_quote_request(
+    request_ai: &AccountInfo,
+    program_id: &Pubkey,
+) -> Result<QuoteRequest, ProgramError> {
+    let request = load_account::<QuoteRequest>(request_ai, program_id)?;
+    let expected = Pubkey::create_program_address(
+        &[
+            b"rfq",
+            request.user.as_ref(),
+            &request.nonce.to_le_bytes(),
+            &[request.bump],
+        ],
+        program_id,
+    )
+    .map_err(|_| EngineError::InvalidAccountAddress)?;
+    if &expected != request_ai.key {
+        return Err(EngineError::InvalidAccountAddress.into());
+    }
+    Ok(request)
+}
+
+/// Derives the address of the quote of `maker` for a quote request.
+pub fn find_quote_address(program_id: &Pubkey, request: &Pubkey, maker: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"quote", request.as_ref(), maker.as_ref()], program_id)
+}
+
+/// Loads a quote for `request` and verifies its address.
+pub fn load_quote(
+    quote_ai: &AccountInfo,
+    program_id: &Pubkey,
+    request: &Pubkey,
+) -> Result<Quote, ProgramError> {
+    let quote = load_account::<Quote>(quote_ai, program_id)?;
+    let expected = Pubkey::create_program_address(
+        &[
+            b"quote",
+            request.as_ref(),
+            quote.maker.as_ref(),
+            &[quote.bump],
+        ],
+        program_id,
+    )
+    .map_err(|_| EngineError::InvalidAccountAddress)?;
+    if &quote.request != request || &expected != quote_ai.key {
+        return Err(EngineError::InvalidAccountAddress.into());
+    }
+    Ok(quote)
+}
+
+/// Requires `admin_ai` to be the market admin and to have signed.
+pub fn assert_admin(market: &Market, admin_ai: &AccountInfo) -> ProgramResult {
+    if !admin_ai.is_signer || admin_ai.key != &market.admin {
+        return Err(EngineError::Unauthorized.into());
+    }
+    Ok(())
+}
+
+/// Requires `authority_ai` to be the owner of `user` and to have signed.
+pub fn assert_owner(user: &UserAccount, authority_ai: &AccountInfo) -> ProgramResult {
+    if !authority_ai.is_signer || authority_ai.key != &user.owner {
+        return Err(EngineError::Unauthorized.into());
+    }
+    Ok(())
+}
+
+/// Requires `authority_ai` to be the owner or trading delegate of `user`
+/// and to have signed.
+pub fn assert_owner_or_delegate(user: &UserAccount, authority_ai: &AccountInfo) -> ProgramResult {
+    let is_delegate = user.delegate != Pubkey::default() && authority_ai.key == &user.delegate;
+    if !authority_ai.is_signer || (authority_ai.key != &user.owner && !is_delegate) {
+        return Err(EngineError::Unauthorized.into());
+    }
+    Ok(())
+}
+
+/// Zeroes an account's data and moves all of its lamports to `destination`.
+pub fn close_account(account: &AccountInfo, destination: &AccountInfo) -> ProgramResult {
+    let lamports = account.lamports();
+    **destination.try_borrow_mut_lamports()? = destination
+        .lamports()
+        .checked_add(lamports)
+        .ok_or(EngineError::MathError)?;
+    **account.try_borrow_mut_lamports()? = 0;
+    account.try_borrow_mut_data()?.fill(0);
+    Ok(())
+}
+
+/// Allocates a rent-exempt, program-owned account at a PDA. Handles
+/// addresses that were pre-funded with lamports before creation.
+pub fn create_pda_account<'a>(
+    program_id: &Pubkey,
+    payer_ai: &AccountInfo<'a>,
+    new_ai: &AccountInfo<'a>,
+    system_program_ai: &AccountInfo<'a>,
+    space: usize,
+    seeds: &[&[u8]],
+) -> ProgramResult {
+    let required = Rent::get()?.minimum_balance(space);
+    let current = new_ai.lamports();
+
+    if current == 0 {
+        return invoke_signed(
+            &system_instruction::create_account(
+                payer_ai.key,
+                new_ai.key,
+                required,
+                space as u64,
+                program_id,
+            ),
+            &[payer_ai.clone(), new_ai.clone(), system_program_ai.clone()],
+            &[seeds],
+        );
+    }
+
+    if current < required {
+        invoke(
+            &system_instruction::transfer(payer_ai.key, new_ai.key, required - current),
+            &[payer_ai.clone(), new_ai.clone(), system_program_ai.clone()],
+        )?;
+    }
+    invoke_signed(
+        &system_instruction::allocate(new_ai.key, space as u64),
+        &[new_ai.clone(), system_program_ai.clone()],
+        &[seeds],
+    )?;
+    invoke_signed(
+        &system_instruction::assign(new_ai.key, program_id),
+        &[new_ai.clone(), system_program_ai.clone()],
+        &[seeds],
+    )
+}
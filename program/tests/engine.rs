@@ -1,10 +1,213 @@
-use borsh::{to_vec, BorshDeserialize};
+use borsh::{to_vec, BorshDeserialize, BorshSerialize};
 use matching_engine::{
-    instruction::EngineInstruction,
-    matching::match_orders,
-    state::{Order, UserAccount},
+    error::EngineError,
+    instruction::{
+        EngineInstruction, MarketParamsUpdate, OrderParams, MAX_BATCH_MAKER_SLOTS, MAX_BATCH_ORDERS,
+    },
+    matching::{
+        auction_clearing_price, cancel_orders, clear_auction, match_orders, prune_expired_orders,
+        reduce_only_capacity, reducing_capacity, rest_order, settle_trade,
+    },
+    pnl::settle_pnl,
+    processor::Processor,
+    queue::{push_event, push_events, read_event, EventQueueHeader},
+    state::{
+        AccountType, Event, Market, MarketAction, MarketKind, MarketStatus, MatchingMode,
+        OraclePrice, Order, OrderType, Quote, QuoteRequest, RfqStatus, Side, TriggerDirection,
+        TriggerOrder, TriggerOrders, TwapOrder, TwapStatus, UserAccount,
+    },
+    token::{unpack_token_account, TOKEN_ACCOUNT_LEN},
+    utils::{
+        assert_owner, assert_owner_or_delegate, find_quote_address, find_quote_request_address,
+        find_trigger_orders_address, find_twap_order_address, find_user_account_address,
+        load_from_data, load_user_account, store_to_data,
+    },
 };
-use solana_program::pubkey::Pubkey;
+use solana_program::{
+    account_info::AccountInfo,
+    clock::Clock,
+    entrypoint::{ProgramResult, SUCCESS},
+    program_stubs::{set_syscall_stubs, SyscallStubs},
+    pubkey::Pubkey,
+    rent::Rent,
+};
+use std::cell::Cell;
+use std::sync::Once;
+
+fn test_user() -> UserAccount {
+    UserAccount {
+        owner: Pubkey::new_unique(),
+        market: Pubkey::new_unique(),
+        delegate: Pubkey::default(),
+        account_num: 0,
+        bump: 0,
+        base_position: 0,
+        quote_position: 0,
+        last_update_ts: 0,
+        net_deposits_lots: 0,
+        settled_pnl_lots: 0,
+        pending_pnl_lots: 0,
+        pending_pnl_ts: 0,
+        locked_base_lots: 0,
+        locked_quote_lots: 0,
+        open_orders: vec![Order::default(); 8],
+    }
+}
+
+fn test_market() -> Market {
+    Market {
+        admin: Pubkey::new_unique(),
+        pending_admin: Pubkey::default(),
+        base_mint: Pubkey::default(),
+        quote_mint: Pubkey::default(),
+        oracle: Pubkey::new_unique(),
+        event_queue: Pubkey::new_unique(),
+        fee_bps: 10,
+        init_margin_bps: Market::DEFAULT_INIT_MARGIN_BPS,
+        maint_margin_bps: Market::DEFAULT_MAINT_MARGIN_BPS,
+        status: MarketStatus::Active,
+        padding: [0; 5],
+        tick_size: 1,
+        base_lot_size: 100,
+        quote_lot_size: 10,
+        min_base_order_size: 1,
+        max_price_deviation_bps: 0,
+        keeper_fee_lots: Market::DEFAULT_KEEPER_FEE_LOTS,
+        matching_mode: MatchingMode::Continuous,
+        auction_interval_secs: 0,
+        auction_end_ts: 0,
+        pnl_maturity_secs: Market::DEFAULT_PNL_MATURITY_SECS,
+        kind: MarketKind::Perp,
+        base_vault: Pubkey::default(),
+        quote_vault: Pubkey::default(),
+        vault_bump: 0,
+        seq_num: 0,
+    }
+}
+
+thread_local! {
+    static NOW: Cell<i64> = const { Cell::new(0) };
+}
+
+/// Serves the clock and rent sysvars to processor tests. The clock reads
+/// the calling test thread's `NOW`, so tests can run in parallel.
+struct TestSyscalls;
+
+impl SyscallStubs for TestSyscalls {
+    fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
+        let clock = Clock {
+            unix_timestamp: NOW.with(Cell::get),
+            ..Clock::default()
+        };
+        unsafe { *(var_addr as *mut Clock) = clock };
+        SUCCESS
+    }
+
+    fn sol_get_rent_sysvar(&self, var_addr: *mut u8) -> u64 {
+        unsafe { *(var_addr as *mut Rent) = Rent::default() };
+        SUCCESS
+    }
+}
+
+fn set_clock(now: i64) {
+    static STUBS: Once = Once::new();
+    STUBS.call_once(|| {
+        set_syscall_stubs(Box::new(TestSyscalls));
+    });
+    NOW.with(|n| n.set(now));
+}
+
+/// Backing storage for an account passed to `Processor::process`.
+struct TestAccount {
+    key: Pubkey,
+    owner: Pubkey,
+    is_signer: bool,
+    lamports: u64,
+    data: Vec<u8>,
+}
+
+impl TestAccount {
+    fn program<T: AccountType + BorshSerialize>(key: Pubkey, value: &T, len: usize) -> Self {
+        let mut data = vec![0u8; len];
+        store_to_data(&mut data, value).expect("store");
+        Self {
+            key,
+            owner: matching_engine::id(),
+            is_signer: false,
+            lamports: 1_000_000,
+            data,
+        }
+    }
+
+    fn signer(key: Pubkey) -> Self {
+        Self {
+            key,
+            owner: Pubkey::default(),
+            is_signer: true,
+            lamports: 0,
+            data: Vec::new(),
+        }
+    }
+
+    fn info(&mut self) -> AccountInfo<'_> {
+        AccountInfo::new(
+            &self.key,
+            self.is_signer,
+            true,
+            &mut self.lamports,
+            &mut self.data,
+            &self.owner,
+            false,
+            0,
+        )
+    }
+
+    fn load<T: AccountType + BorshDeserialize>(&self) -> T {
+        load_from_data(&self.data).expect("load")
+    }
+}
+
+/// Market, event queue and oracle accounts for `market`, which is updated
+/// to point at the queue and oracle.
+fn market_accounts(market: &mut Market, oracle_price: i64) -> [TestAccount; 3] {
+    market.event_queue = Pubkey::new_unique();
+    market.oracle = Pubkey::new_unique();
+    let oracle = OraclePrice {
+        price: oracle_price,
+        confidence: 0,
+        last_updated_slot: 0,
+    };
+    let queue = TestAccount {
+        key: market.event_queue,
+        owner: matching_engine::id(),
+        is_signer: false,
+        lamports: 1_000_000,
+        data: vec![0u8; EventQueueHeader::DATA_OFFSET + Event::SLOT_SIZE * 16],
+    };
+    [
+        TestAccount::program(Pubkey::new_unique(), &*market, 1_024),
+        queue,
+        TestAccount::program(market.oracle, &oracle, 64),
+    ]
+}
+
+/// User account for `user` on `market`, stored at its PDA.
+fn user_account(market: &Pubkey, mut user: UserAccount) -> TestAccount {
+    let (key, bump) = find_user_account_address(
+        &matching_engine::id(),
+        market,
+        &user.owner,
+        user.account_num,
+    );
+    user.market = *market;
+    user.bump = bump;
+    TestAccount::program(key, &user, UserAccount::space(user.open_orders.len()))
+}
+
+fn process(accounts: &mut [&mut TestAccount], ix: &EngineInstruction) -> ProgramResult {
+    let infos: Vec<_> = accounts.iter_mut().map(|a| a.info()).collect();
+    Processor::process(&matching_engine::id(), &infos, &to_vec(ix).unwrap())
+}
 
 #[test]
 fn instruction_roundtrip() {
@@ -12,6 +215,11 @@ fn instruction_roundtrip() {
         price_lots: 100,
         max_base_lots: 10,
         side_is_bid: true,
+        order_type: OrderType::ImmediateOrCancel,
+        expiry_timestamp: Some(1_700_000_000),
+        reduce_only: false,
+        peg: None,
+        display_base_lots: Some(5),
     };
 
     let encoded = to_vec(&ix).expect("serialize");
@@ -22,10 +230,20 @@ fn instruction_roundtrip() {
             price_lots,
             max_base_lots,
             side_is_bid,
+            order_type,
+            expiry_timestamp,
+            reduce_only,
+            peg,
+            display_base_lots,
         } => {
             assert_eq!(price_lots, 100);
             assert_eq!(max_base_lots, 10);
             assert!(side_is_bid);
+            assert_eq!(order_type, OrderType::ImmediateOrCancel);
+            assert_eq!(expiry_timestamp, Some(1_700_000_000));
+            assert!(!reduce_only);
+            assert_eq!(peg, None);
+            assert_eq!(display_base_lots, Some(5));
         }
         _ => panic!("unexpected variant"),
     }
@@ -33,46 +251,2840 @@ fn instruction_roundtrip() {
 
 #[test]
 fn simple_matching_flow() {
-    let mut taker = UserAccount {
-        owner: Pubkey::new_unique(),
-        market: Pubkey::new_unique(),
-        base_position: 0,
-        quote_position: 0,
-        last_update_ts: 0,
-        open_orders: [Order::default(); 8],
-    };
+    let mut taker = test_user();
 
-    let mut maker = UserAccount {
-        owner: Pubkey::new_unique(),
+    let maker = UserAccount {
         market: taker.market,
         base_position: 100,
-        quote_position: 0,
-        last_update_ts: 0,
-        open_orders: [Order {
-            id: 1,
-            price_lots: 50,
-            base_lots: 20,
-            side_is_bid: false,
-            is_active: true,
-        }; 8],
+        open_orders: vec![
+            Order {
+                id: 1,
+                price_lots: 50,
+                base_lots: 20,
+                side_is_bid: false,
+                is_active: true,
+                expiry_ts: 0,
+                reduce_only: false,
+                is_pegged: false,
+                peg_offset_lots: 0,
+                peg_limit_lots: 0,
+                display_base_lots: 0,
+                hidden_base_lots: 0,
+            };
+            8
+        ],
+        ..test_user()
     };
 
-    let mut makers = vec![maker];
+    let mut makers = [maker];
     let mut events = Vec::new();
     let mut max_quote_change = 0i64;
 
-    match_orders(
+    let remaining = match_orders(
         &mut taker,
         &mut makers[..],
+        50,
         10,
         &mut max_quote_change,
         true,
+        0,
+        &mut 0,
+        0,
         &mut events,
     );
 
+    assert_eq!(remaining, 0);
     assert_eq!(taker.base_position, 10);
     assert_eq!(taker.quote_position, -500);
     assert_eq!(makers[0].base_position, 90);
     assert_eq!(makers[0].quote_position, 500);
     assert_eq!(events.len(), 1);
 }
+
+#[test]
+fn discriminator_prevents_type_confusion() {
+    let header = EventQueueHeader {
+        head: 0,
+        tail: 0,
+        capacity: 64,
+    };
+    let mut data = vec![0u8; 512];
+    store_to_data(&mut data, &header).expect("store");
+
+    let err = load_from_data::<UserAccount>(&data).unwrap_err();
+    assert_eq!(err, EngineError::InvalidAccountType.into());
+
+    let loaded = load_from_data::<EventQueueHeader>(&data).expect("load");
+    assert_eq!(loaded.capacity, 64);
+
+    data[8] = EventQueueHeader::VERSION + 1;
+    let err = load_from_data::<EventQueueHeader>(&data).unwrap_err();
+    assert_eq!(err, EngineError::InvalidAccountVersion.into());
+}
+
+#[test]
+fn user_account_must_live_at_its_pda() {
+    let program_id = Pubkey::new_unique();
+    let market = Pubkey::new_unique();
+    let owner = Pubkey::new_unique();
+    let (address, bump) = find_user_account_address(&program_id, &market, &owner, 3);
+
+    let user = UserAccount {
+        owner,
+        market,
+        account_num: 3,
+        bump,
+        ..test_user()
+    };
+    let mut data = vec![0u8; UserAccount::space(8)];
+    store_to_data(&mut data, &user).expect("store");
+
+    let mut lamports = 0u64;
+    let ai = AccountInfo::new(
+        &address,
+        false,
+        true,
+        &mut lamports,
+        &mut data,
+        &program_id,
+        false,
+        0,
+    );
+    assert!(load_user_account(&ai, &program_id, &market).is_ok());
+    let err = load_user_account(&ai, &program_id, &Pubkey::new_unique()).unwrap_err();
+    assert_eq!(err, EngineError::InvalidAccountAddress.into());
+
+    let wrong_address = Pubkey::new_unique();
+    let mut lamports = 0u64;
+    let mut data_copy = ai.data.borrow().to_vec();
+    let ai = AccountInfo::new(
+        &wrong_address,
+        false,
+        true,
+        &mut lamports,
+        &mut data_copy,
+        &program_id,
+        false,
+        0,
+    );
+    let err = load_user_account(&ai, &program_id, &market).unwrap_err();
+    assert_eq!(err, EngineError::InvalidAccountAddress.into());
+}
+
+#[test]
+fn queued_trade_events_reference_both_owners() {
+    let maker = Pubkey::new_unique();
+    let taker = Pubkey::new_unique();
+    let mut header = EventQueueHeader {
+        head: 0,
+        tail: 0,
+        capacity: 4,
+    };
+    let mut buf = vec![0u8; Event::SLOT_SIZE * 4];
+
+    for _ in 0..5 {
+        let event = Event::Trade {
+            maker,
+            maker_account_num: 0,
+            taker,
+            taker_account_num: 1,
+            price_lots: 50,
+            base_lots: 2,
+        };
+        push_event(&mut header, &mut buf, &event).expect("push");
+    }
+    assert_eq!(header.tail - header.head, 4);
+
+    let event = read_event(&header, &buf, header.head).expect("read");
+    assert!(event.references(&maker, 0));
+    assert!(event.references(&taker, 1));
+    assert!(!event.references(&taker, 0));
+    assert!(!event.references(&Pubkey::new_unique(), 0));
+}
+
+#[test]
+fn unmatched_quantity_rests_in_configured_slots() {
+    let market = Pubkey::new_unique();
+    let mut taker = UserAccount {
+        market,
+        open_orders: vec![Order::default(); 24],
+        ..test_user()
+    };
+    let ask = Order {
+        id: 1,
+        price_lots: 60,
+        base_lots: 5,
+        side_is_bid: false,
+        is_active: true,
+        expiry_ts: 0,
+        reduce_only: false,
+        is_pegged: false,
+        peg_offset_lots: 0,
+        peg_limit_lots: 0,
+        display_base_lots: 0,
+        hidden_base_lots: 0,
+    };
+    let mut makers = [UserAccount {
+        owner: Pubkey::new_unique(),
+        open_orders: vec![ask; 1],
+        ..taker.clone()
+    }];
+
+    let mut events = Vec::new();
+    let mut max_quote_change = 0i64;
+    let remaining = match_orders(
+        &mut taker,
+        &mut makers[..],
+        55,
+        5,
+        &mut max_quote_change,
+        true,
+        0,
+        &mut 0,
+        0,
+        &mut events,
+    );
+    assert_eq!(remaining, 5);
+    assert!(events.is_empty());
+
+    for level in 0..24 {
+        let order = Order {
+            id: level,
+            price_lots: 55 - level as i64,
+            base_lots: 1,
+            side_is_bid: true,
+            is_active: true,
+            expiry_ts: 0,
+            reduce_only: false,
+            is_pegged: false,
+            peg_offset_lots: 0,
+            peg_limit_lots: 0,
+            display_base_lots: 0,
+            hidden_base_lots: 0,
+        };
+        rest_order(&mut taker, order).expect("free slot");
+    }
+    assert_eq!(
+        rest_order(&mut taker, Order::default()).unwrap_err(),
+        EngineError::OrderSlotsFull
+    );
+
+    let mut data = vec![0u8; UserAccount::space(24)];
+    store_to_data(&mut data, &taker).expect("fits");
+    let loaded = load_from_data::<UserAccount>(&data).expect("load");
+    assert_eq!(loaded.open_orders.len(), 24);
+}
+
+#[test]
+fn delegate_can_trade_but_not_withdraw() {
+    let program_id = Pubkey::new_unique();
+    let owner = Pubkey::new_unique();
+    let delegate = Pubkey::new_unique();
+    let user = UserAccount {
+        owner,
+        delegate,
+        ..test_user()
+    };
+
+    let mut lamports = 0u64;
+    let mut data = [];
+    let delegate_ai = AccountInfo::new(
+        &delegate,
+        true,
+        false,
+        &mut lamports,
+        &mut data,
+        &program_id,
+        false,
+        0,
+    );
+    assert!(assert_owner_or_delegate(&user, &delegate_ai).is_ok());
+    assert_eq!(
+        assert_owner(&user, &delegate_ai).unwrap_err(),
+        EngineError::Unauthorized.into()
+    );
+
+    let mut lamports = 0u64;
+    let mut data = [];
+    let owner_ai = AccountInfo::new(
+        &owner,
+        true,
+        false,
+        &mut lamports,
+        &mut data,
+        &program_id,
+        false,
+        0,
+    );
+    assert!(assert_owner(&user, &owner_ai).is_ok());
+    assert!(assert_owner_or_delegate(&user, &owner_ai).is_ok());
+}
+
+#[test]
+fn delegates_cannot_withdraw_or_close_through_the_processor() {
+    set_clock(0);
+    let mut market = test_market();
+    let [mut market_ai, mut queue_ai, mut oracle_ai] = market_accounts(&mut market, 100);
+    let owner = Pubkey::new_unique();
+    let delegate = Pubkey::new_unique();
+    let mut user_ai = user_account(
+        &market_ai.key,
+        UserAccount {
+            owner,
+            delegate,
+            quote_position: 1_000,
+            net_deposits_lots: 1_000,
+            ..test_user()
+        },
+    );
+    let mut owner_ai = TestAccount::signer(owner);
+    let mut delegate_ai = TestAccount::signer(delegate);
+    let unauthorized = Err(EngineError::Unauthorized.into());
+    let withdraw = EngineInstruction::Withdraw {
+        amount: 1_000 * market.quote_lot_size,
+    };
+
+    assert_eq!(
+        process(
+            &mut [
+                &mut market_ai,
+                &mut user_ai,
+                &mut delegate_ai,
+                &mut oracle_ai,
+            ],
+            &withdraw,
+        ),
+        unauthorized
+    );
+    process(
+        &mut [&mut market_ai, &mut user_ai, &mut owner_ai, &mut oracle_ai],
+        &withdraw,
+    )
+    .unwrap();
+
+    let close = EngineInstruction::CloseUserAccount;
+    assert_eq!(
+        process(
+            &mut [
+                &mut market_ai,
+                &mut user_ai,
+                &mut delegate_ai,
+                &mut queue_ai,
+            ],
+            &close,
+        ),
+        unauthorized
+    );
+    process(
+        &mut [&mut market_ai, &mut user_ai, &mut owner_ai, &mut queue_ai],
+        &close,
+    )
+    .unwrap();
+    assert_eq!((owner_ai.lamports, delegate_ai.lamports), (1_000_000, 0));
+}
+
+#[test]
+fn market_params_update_only_touches_given_fields() {
+    let mut market = test_market();
+    let oracle = market.oracle;
+
+    let update = MarketParamsUpdate {
+        fee_bps: Some(25),
+        tick_size: Some(5),
+        ..Default::default()
+    };
+    update.apply(&mut market);
+    assert!(market.validate_params().is_ok());
+    assert_eq!(market.fee_bps, 25);
+    assert_eq!(market.tick_size, 5);
+    assert_eq!(market.oracle, oracle);
+
+    let update = MarketParamsUpdate {
+        maint_margin_bps: Some(market.init_margin_bps + 1),
+        ..Default::default()
+    };
+    update.apply(&mut market);
+    assert_eq!(
+        market.validate_params().unwrap_err(),
+        EngineError::InvalidMarketParams
+    );
+}
+
+#[test]
+fn market_setup_is_admin_only_and_happens_once() {
+    set_clock(0);
+    let mut market = test_market();
+    let [mut market_ai, mut queue_ai, mut oracle_ai] = market_accounts(&mut market, 100);
+    market_ai.data.fill(0);
+    market_ai.lamports = 10_000_000;
+    let admin = Pubkey::new_unique();
+    let mut admin_ai = TestAccount::signer(admin);
+    let init = EngineInstruction::InitializeMarket {
+        fee_bps: 10,
+        base_lot_size: 100,
+        quote_lot_size: 10,
+        tick_size: 1,
+        min_base_order_size: 1,
+        kind: MarketKind::Perp,
+    };
+    process(
+        &mut [&mut market_ai, &mut admin_ai, &mut oracle_ai, &mut queue_ai],
+        &init,
+    )
+    .unwrap();
+    assert_eq!(market_ai.load::<Market>().admin, admin);
+
+    // An initialized market cannot be taken over by initializing it again.
+    let mut stranger_ai = TestAccount::signer(Pubkey::new_unique());
+    assert_eq!(
+        process(
+            &mut [
+                &mut market_ai,
+                &mut stranger_ai,
+                &mut oracle_ai,
+                &mut queue_ai
+            ],
+            &init,
+        ),
+        Err(EngineError::AlreadyInitialized.into())
+    );
+
+    let update = EngineInstruction::UpdateMarketParams {
+        params: MarketParamsUpdate {
+            fee_bps: Some(20),
+            ..MarketParamsUpdate::default()
+        },
+    };
+    let unauthorized = Err(EngineError::Unauthorized.into());
+    assert_eq!(
+        process(
+            &mut [&mut market_ai, &mut stranger_ai, &mut queue_ai],
+            &update
+        ),
+        unauthorized
+    );
+    admin_ai.is_signer = false;
+    assert_eq!(
+        process(&mut [&mut market_ai, &mut admin_ai, &mut queue_ai], &update),
+        unauthorized
+    );
+    admin_ai.is_signer = true;
+    process(&mut [&mut market_ai, &mut admin_ai, &mut queue_ai], &update).unwrap();
+    assert_eq!(market_ai.load::<Market>().fee_bps, 20);
+}
+
+#[test]
+fn market_status_permission_matrix() {
+    use MarketAction::*;
+    use MarketStatus::*;
+
+    let actions = [
+        CreateUserAccount,
+        Deposit,
+        Withdraw,
+        PlaceOrder,
+        PlaceReducingOrder,
+        CancelOrder,
+        Liquidate,
+    ];
+    let expected = [
+        (Active, [true, true, true, true, true, true, true]),
+        (ReduceOnly, [true, true, true, false, true, true, true]),
+        (Paused, [true, true, true, false, false, true, false]),
+        (Settling, [false, false, true, false, false, true, false]),
+        (Closed, [false, false, true, false, false, true, false]),
+        (PreOpen, [true, true, true, true, true, true, false]),
+    ];
+
+    for (status, allowed) in expected {
+        for (action, allowed) in actions.iter().zip(allowed) {
+            assert_eq!(
+                status.allows(*action),
+                allowed,
+                "{:?} / {:?}",
+                status,
+                action
+            );
+        }
+    }
+
+    let mut market = test_market();
+    market.status = Paused;
+    assert_eq!(
+        market.check_allowed(PlaceOrder).unwrap_err(),
+        EngineError::MarketInactive
+    );
+    assert!(Paused.can_transition_to(Active));
+    assert!(!Closed.can_transition_to(Active));
+    assert!(!Active.can_transition_to(Active));
+    assert!(Paused.can_transition_to(PreOpen));
+    assert!(!PreOpen.can_transition_to(Active));
+}
+
+#[test]
+fn reducing_orders_cannot_flip_position() {
+    let mut user = UserAccount {
+        base_position: 10,
+        ..test_user()
+    };
+    assert_eq!(reducing_capacity(&user, false), 10);
+    assert_eq!(reducing_capacity(&user, true), 0);
+    // Asks already resting against the long count towards it.
+    user.open_orders[0] = Order {
+        base_lots: 4,
+        is_active: true,
+        ..Order::default()
+    };
+    assert_eq!(reducing_capacity(&user, false), 6);
+    user.base_position = -5;
+    assert_eq!(reducing_capacity(&user, true), 5);
+    user.base_position = 0;
+    assert_eq!(reducing_capacity(&user, true), 0);
+}
+
+#[test]
+fn orders_and_amounts_respect_lot_and_tick_sizes() {
+    let mut market = test_market();
+    market.tick_size = 5;
+    market.min_base_order_size = 2;
+
+    assert!(market.validate_order(50, 2).is_ok());
+    assert_eq!(
+        market.validate_order(52, 2).unwrap_err(),
+        EngineError::InvalidOrderParams
+    );
+    assert_eq!(
+        market.validate_order(50, 1).unwrap_err(),
+        EngineError::InvalidOrderParams
+    );
+    assert_eq!(
+        market.validate_order(-5, 2).unwrap_err(),
+        EngineError::InvalidOrderParams
+    );
+
+    assert_eq!(market.native_quote_to_lots(1_230), Ok(123));
+    assert_eq!(
+        market.native_quote_to_lots(1_235),
+        Err(EngineError::InvalidLotAmount)
+    );
+    assert_eq!(market.quote_lots_to_native(123), Ok(1_230));
+    assert_eq!(market.native_base_to_lots(500), Ok(5));
+    assert_eq!(market.base_lots_to_native(5), Ok(500));
+    assert_eq!(market.base_lots_to_native(-1), Err(EngineError::MathError));
+}
+
+#[test]
+fn price_band_rejects_or_clamps_far_orders() {
+    let mut market = test_market();
+    market.tick_size = 5;
+    market.max_price_deviation_bps = 500;
+    let oracle = 1_000;
+
+    assert_eq!(
+        market.apply_price_band(1_040, true, OrderType::Limit, oracle),
+        Ok(1_040)
+    );
+    assert_eq!(
+        market.apply_price_band(1_200, true, OrderType::Limit, oracle),
+        Err(EngineError::PriceOutsideBand)
+    );
+    assert_eq!(
+        market.apply_price_band(1_200, true, OrderType::ImmediateOrCancel, oracle),
+        Ok(1_050)
+    );
+    assert_eq!(
+        market.apply_price_band(800, false, OrderType::ImmediateOrCancel, oracle),
+        Ok(950)
+    );
+    assert_eq!(
+        market.apply_price_band(800, true, OrderType::ImmediateOrCancel, oracle),
+        Err(EngineError::PriceOutsideBand)
+    );
+
+    market.max_price_deviation_bps = 0;
+    assert_eq!(
+        market.apply_price_band(5_000, true, OrderType::Limit, oracle),
+        Ok(5_000)
+    );
+}
+
+#[test]
+fn cancel_all_pulls_one_side_up_to_limit() {
+    let mut user = test_user();
+    for id in 0..6 {
+        let order = Order {
+            id,
+            price_lots: 100,
+            base_lots: 1,
+            side_is_bid: id % 2 == 0,
+            is_active: true,
+            expiry_ts: 0,
+            reduce_only: false,
+            is_pegged: false,
+            peg_offset_lots: 0,
+            peg_limit_lots: 0,
+            display_base_lots: 0,
+            hidden_base_lots: 0,
+        };
+        rest_order(&mut user, order).expect("free slot");
+    }
+
+    let mut events = Vec::new();
+    assert_eq!(cancel_orders(&mut user, Some(Side::Bid), 2, &mut events), 2);
+    assert_eq!(events.len(), 2);
+    assert!(events.iter().all(|e| matches!(
+        e,
+        Event::Out {
+            side_is_bid: true,
+            ..
+        }
+    )));
+
+    assert_eq!(cancel_orders(&mut user, None, 10, &mut events), 4);
+    assert!(user.open_orders.iter().all(|o| !o.is_active));
+}
+
+#[test]
+fn matching_uses_price_then_time_priority() {
+    let market = Pubkey::new_unique();
+    let blank = UserAccount {
+        market,
+        open_orders: vec![Order::default(); 4],
+        ..test_user()
+    };
+    let ask = |id: u128, price_lots: i64| Order {
+        id,
+        price_lots,
+        base_lots: 5,
+        side_is_bid: false,
+        is_active: true,
+        expiry_ts: 0,
+        reduce_only: false,
+        is_pegged: false,
+        peg_offset_lots: 0,
+        peg_limit_lots: 0,
+        display_base_lots: 0,
+        hidden_base_lots: 0,
+    };
+
+    let mut taker = blank.clone();
+    let mut late = UserAccount {
+        owner: Pubkey::new_unique(),
+        ..blank.clone()
+    };
+    late.open_orders[0] = ask(7, 100);
+    let mut cheap = UserAccount {
+        owner: Pubkey::new_unique(),
+        ..blank.clone()
+    };
+    cheap.open_orders[0] = ask(9, 99);
+    let mut early = UserAccount {
+        owner: Pubkey::new_unique(),
+        ..blank
+    };
+    early.open_orders[0] = ask(3, 100);
+
+    let mut makers = [late, cheap, early];
+    let mut events = Vec::new();
+    let mut max_quote_change = 0i64;
+    let remaining = match_orders(
+        &mut taker,
+        &mut makers[..],
+        100,
+        10,
+        &mut max_quote_change,
+        true,
+        0,
+        &mut 0,
+        0,
+        &mut events,
+    );
+
+    assert_eq!(remaining, 0);
+    let fills: Vec<_> = events
+        .iter()
+        .map(|e| match e {
+            Event::Trade {
+                maker, price_lots, ..
+            } => (*maker, *price_lots),
+            _ => panic!("unexpected event"),
+        })
+        .collect();
+    assert_eq!(fills, vec![(makers[1].owner, 99), (makers[2].owner, 100)]);
+    assert!(makers[0].open_orders[0].is_active);
+}
+
+#[test]
+fn expired_orders_are_skipped_and_pruned() {
+    let market = Pubkey::new_unique();
+    let mut taker = UserAccount {
+        market,
+        open_orders: vec![Order::default(); 4],
+        ..test_user()
+    };
+    let ask = |id: u128, expiry_ts: i64| Order {
+        id,
+        price_lots: 100,
+        base_lots: 5,
+        side_is_bid: false,
+        is_active: true,
+        expiry_ts,
+        reduce_only: false,
+        is_pegged: false,
+        peg_offset_lots: 0,
+        peg_limit_lots: 0,
+        display_base_lots: 0,
+        hidden_base_lots: 0,
+    };
+    let mut maker = UserAccount {
+        owner: Pubkey::new_unique(),
+        ..taker.clone()
+    };
+    maker.open_orders[0] = ask(1, 1_000);
+    maker.open_orders[1] = ask(2, 0);
+    maker.open_orders[2] = ask(3, 2_000);
+
+    let mut makers = [maker];
+    let mut events = Vec::new();
+    let mut max_quote_change = 0i64;
+    let remaining = match_orders(
+        &mut taker,
+        &mut makers[..],
+        100,
+        5,
+        &mut max_quote_change,
+        true,
+        0,
+        &mut 0,
+        1_500,
+        &mut events,
+    );
+
+    assert_eq!(remaining, 0);
+    assert!(matches!(events[0], Event::Out { order_id: 1, .. }));
+    assert!(matches!(events[1], Event::Trade { base_lots: 5, .. }));
+    assert!(!makers[0].open_orders[0].is_active);
+    assert!(!makers[0].open_orders[1].is_active);
+    assert!(makers[0].open_orders[2].is_active);
+
+    let mut events = Vec::new();
+    assert_eq!(
+        prune_expired_orders(&mut makers[0], 1_999, 8, &mut events),
+        0
+    );
+    assert_eq!(
+        prune_expired_orders(&mut makers[0], 2_000, 8, &mut events),
+        1
+    );
+    assert!(matches!(events[0], Event::Out { order_id: 3, .. }));
+}
+
+#[test]
+fn reduce_only_orders_never_flip_positions() {
+    let mut user = UserAccount {
+        base_position: 10,
+        open_orders: vec![Order::default(); 4],
+        ..test_user()
+    };
+    assert_eq!(reduce_only_capacity(&user, false), 10);
+    assert_eq!(reduce_only_capacity(&user, true), 0);
+
+    user.open_orders[0] = Order {
+        id: 1,
+        price_lots: 100,
+        base_lots: 6,
+        side_is_bid: false,
+        is_active: true,
+        expiry_ts: 0,
+        reduce_only: true,
+        is_pegged: false,
+        peg_offset_lots: 0,
+        peg_limit_lots: 0,
+        display_base_lots: 0,
+        hidden_base_lots: 0,
+    };
+    assert_eq!(reduce_only_capacity(&user, false), 4);
+
+    // A resting reduce-only ask only fills down to a flat position.
+    user.base_position = 2;
+    let mut taker = UserAccount {
+        owner: Pubkey::new_unique(),
+        base_position: 0,
+        open_orders: vec![Order::default(); 4],
+        ..user.clone()
+    };
+    let mut makers = [user];
+    let mut events = Vec::new();
+    let mut max_quote_change = 0i64;
+    let remaining = match_orders(
+        &mut taker,
+        &mut makers[..],
+        100,
+        6,
+        &mut max_quote_change,
+        true,
+        0,
+        &mut 0,
+        0,
+        &mut events,
+    );
+    assert_eq!(remaining, 4);
+    assert_eq!(makers[0].base_position, 0);
+    assert_eq!(taker.base_position, 2);
+}
+
+#[test]
+fn trigger_orders_fire_on_their_side_of_the_price() {
+    let params = OrderParams {
+        price_lots: 90,
+        max_base_lots: 5,
+        side_is_bid: false,
+        order_type: OrderType::ImmediateOrCancel,
+        expiry_timestamp: None,
+        reduce_only: true,
+        peg: None,
+        display_base_lots: None,
+    };
+    let stop_loss = TriggerOrder {
+        id: 0,
+        trigger_price: 95,
+        direction: TriggerDirection::Below,
+        params: params.clone(),
+    };
+    let take_profit = TriggerOrder {
+        id: 1,
+        trigger_price: 120,
+        direction: TriggerDirection::Above,
+        params,
+    };
+
+    assert!(!stop_loss.is_triggered(100));
+    assert!(stop_loss.is_triggered(95));
+    assert!(stop_loss.is_triggered(80));
+    assert!(!take_profit.is_triggered(119));
+    assert!(take_profit.is_triggered(120));
+
+    // A full store must still fit in the space allocated for it.
+    let store = TriggerOrders {
+        user: Pubkey::new_unique(),
+        bump: 255,
+        next_id: u64::MAX,
+        orders: vec![stop_loss; TriggerOrders::MAX_ORDERS],
+    };
+    let mut data = vec![0u8; TriggerOrders::LEN];
+    store_to_data(&mut data, &store).unwrap();
+    let loaded: TriggerOrders = load_from_data(&data).unwrap();
+    assert_eq!(loaded.orders.len(), TriggerOrders::MAX_ORDERS);
+}
+
+#[test]
+fn pegged_orders_float_with_the_oracle() {
+    let pegged_ask = |id: u128, offset: i64, limit: i64| Order {
+        id,
+        price_lots: 0,
+        base_lots: 5,
+        side_is_bid: false,
+        is_active: true,
+        expiry_ts: 0,
+        reduce_only: false,
+        is_pegged: true,
+        peg_offset_lots: offset,
+        peg_limit_lots: limit,
+        display_base_lots: 0,
+        hidden_base_lots: 0,
+    };
+    assert_eq!(pegged_ask(0, 2, 0).effective_price(100), Some(102));
+    assert_eq!(pegged_ask(0, 2, 105).effective_price(100), Some(105));
+    assert_eq!(pegged_ask(0, -200, 0).effective_price(100), None);
+
+    let mut maker = UserAccount {
+        open_orders: vec![Order::default(); 4],
+        ..test_user()
+    };
+    // A fixed ask at 103 and a pegged ask one tick above the oracle.
+    maker.open_orders[0] = Order {
+        price_lots: 103,
+        is_pegged: false,
+        ..pegged_ask(1, 0, 0)
+    };
+    maker.open_orders[1] = pegged_ask(2, 1, 0);
+    let mut taker = UserAccount {
+        owner: Pubkey::new_unique(),
+        ..maker.clone()
+    };
+    taker.open_orders = vec![Order::default(); 4];
+    let mut makers = [maker];
+
+    // With the oracle at 100 the pegged ask is best and fills first.
+    let mut events = Vec::new();
+    let mut max_quote_change = 0i64;
+    let remaining = match_orders(
+        &mut taker,
+        &mut makers[..],
+        110,
+        5,
+        &mut max_quote_change,
+        true,
+        100,
+        &mut 0,
+        0,
+        &mut events,
+    );
+    assert_eq!(remaining, 0);
+    assert_eq!(max_quote_change, 5 * 101);
+    assert!(!makers[0].open_orders[1].is_active);
+
+    // Once the oracle moves up the fixed ask becomes the better price.
+    makers[0].open_orders[1] = pegged_ask(2, 1, 0);
+    let mut events = Vec::new();
+    let mut max_quote_change = 0i64;
+    match_orders(
+        &mut taker,
+        &mut makers[..],
+        110,
+        5,
+        &mut max_quote_change,
+        true,
+        105,
+        &mut 0,
+        0,
+        &mut events,
+    );
+    assert_eq!(max_quote_change, 5 * 103);
+    assert!(makers[0].open_orders[1].is_active);
+}
+
+#[test]
+fn iceberg_refills_lose_time_priority() {
+    let ask = |id: u128, base_lots: i64, hidden_base_lots: i64| Order {
+        id,
+        price_lots: 100,
+        base_lots,
+        side_is_bid: false,
+        is_active: true,
+        expiry_ts: 0,
+        reduce_only: false,
+        is_pegged: false,
+        peg_offset_lots: 0,
+        peg_limit_lots: 0,
+        display_base_lots: if hidden_base_lots > 0 { base_lots } else { 0 },
+        hidden_base_lots,
+    };
+    let mut iceberg = UserAccount {
+        open_orders: vec![Order::default(); 4],
+        ..test_user()
+    };
+    iceberg.open_orders[0] = ask(1, 2, 5);
+    let mut plain = UserAccount {
+        owner: Pubkey::new_unique(),
+        ..iceberg.clone()
+    };
+    plain.open_orders = vec![Order::default(); 4];
+    plain.open_orders[0] = ask(2, 3, 0);
+    let mut taker = UserAccount {
+        owner: Pubkey::new_unique(),
+        ..plain.clone()
+    };
+    taker.open_orders = vec![Order::default(); 4];
+    let mut makers = [iceberg, plain];
+
+    // The visible slice fills, the refill queues behind the plain ask.
+    let mut events = Vec::new();
+    let mut max_quote_change = 0i64;
+    let mut seq_num = 10u64;
+    let remaining = match_orders(
+        &mut taker,
+        &mut makers[..],
+        100,
+        6,
+        &mut max_quote_change,
+        true,
+        0,
+        &mut seq_num,
+        0,
+        &mut events,
+    );
+    assert_eq!(remaining, 0);
+    assert_eq!(makers[0].base_position, -3);
+    assert_eq!(makers[1].base_position, -3);
+
+    let refilled = makers[0].open_orders[0];
+    assert_eq!(refilled.id, 10);
+    assert_eq!(refilled.base_lots, 1);
+    assert_eq!(refilled.hidden_base_lots, 3);
+    assert_eq!(seq_num, 11);
+
+    let makers_in_order: Vec<_> = events
+        .iter()
+        .filter_map(|e| match e {
+            Event::Trade { maker, .. } => Some(*maker),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        makers_in_order,
+        vec![makers[0].owner, makers[1].owner, makers[0].owner]
+    );
+    assert!(events.iter().any(|e| matches!(
+        e,
+        Event::Refill {
+            old_order_id: 1,
+            order_id: 10,
+            base_lots: 2,
+            ..
+        }
+    )));
+}
+
+#[test]
+fn twap_slices_catch_up_and_complete() {
+    let mut twap = TwapOrder {
+        user: Pubkey::new_unique(),
+        nonce: 0,
+        bump: 0,
+        status: TwapStatus::Active,
+        side_is_bid: true,
+        limit_price_lots: 100,
+        total_base_lots: 10,
+        filled_base_lots: 0,
+        num_slices: 4,
+        executed_slices: 0,
+        interval_secs: 60,
+        next_slice_ts: 1_000,
+    };
+    assert!(!twap.is_due(999));
+    assert!(twap.is_due(1_000));
+    assert_eq!(twap.next_slice_base_lots(1), 3);
+
+    // A slice stopped by the limit price leaves its size to later slices.
+    twap.record_slice(0, 1);
+    assert_eq!(twap.next_slice_ts, 1_060);
+    assert!(!twap.is_due(1_059));
+    assert_eq!(twap.next_slice_base_lots(1), 4);
+
+    twap.record_slice(4, 1);
+    assert_eq!(twap.next_slice_base_lots(1), 3);
+    assert_eq!(twap.next_slice_base_lots(5), 5);
+    twap.record_slice(3, 1);
+    twap.record_slice(3, 1);
+    assert_eq!(twap.status, TwapStatus::Completed);
+    assert_eq!(twap.filled_base_lots, 10);
+    assert!(!twap.is_due(i64::MAX));
+}
+
+#[test]
+fn auction_clears_at_the_volume_maximizing_price() {
+    let bids = [(102, 5), (101, 5), (100, 5)];
+    let asks = [(99, 4), (100, 4), (101, 6)];
+    assert_eq!(auction_clearing_price(&bids, &asks, 100), Some((101, 10)));
+
+    // Equal volume: the smaller imbalance wins.
+    assert_eq!(
+        auction_clearing_price(&[(101, 5)], &[(100, 5), (101, 3)], 101),
+        Some((100, 5))
+    );
+    // Equal volume and imbalance: closest to the reference, then lower.
+    assert_eq!(
+        auction_clearing_price(&[(100, 5)], &[(98, 5)], 100),
+        Some((100, 5))
+    );
+    assert_eq!(
+        auction_clearing_price(&[(100, 5)], &[(98, 5)], 99),
+        Some((98, 5))
+    );
+    assert_eq!(auction_clearing_price(&[(99, 5)], &[(100, 5)], 100), None);
+    assert_eq!(auction_clearing_price(&[], &[], 100), None);
+}
+
+#[test]
+fn auction_fills_every_crossing_order_at_one_price() {
+    let order = |id: u128, price_lots: i64, base_lots: i64, side_is_bid: bool| Order {
+        id,
+        price_lots,
+        base_lots,
+        side_is_bid,
+        is_active: true,
+        ..Order::default()
+    };
+    let mut buyer = UserAccount {
+        open_orders: vec![Order::default(); 4],
+        ..test_user()
+    };
+    let mut seller = UserAccount {
+        owner: Pubkey::new_unique(),
+        ..buyer.clone()
+    };
+    buyer.open_orders[0] = order(1, 102, 5, true);
+    buyer.open_orders[1] = order(2, 101, 5, true);
+    buyer.open_orders[2] = order(3, 100, 5, true);
+    seller.open_orders[0] = order(4, 99, 4, false);
+    seller.open_orders[1] = order(5, 100, 4, false);
+    seller.open_orders[2] = order(6, 101, 6, false);
+    let mut users = [buyer, seller];
+
+    let mut events = Vec::new();
+    let mut seq_num = 7;
+    let cleared = clear_auction(&mut users, 100, &mut seq_num, 0, &mut events);
+    assert_eq!(cleared, Some((101, 10)));
+
+    assert_eq!(users[0].base_position, 10);
+    assert_eq!(users[0].quote_position, -1010);
+    assert_eq!(users[1].base_position, -10);
+    assert_eq!(users[1].quote_position, 1010);
+    assert!(events.iter().all(|e| matches!(
+        e,
+        Event::Trade {
+            price_lots: 101,
+            ..
+        }
+    )));
+
+    // The bid below the clearing price and the unfilled ask size still rest.
+    assert!(users[0].open_orders[2].is_active);
+    assert_eq!(users[0].open_orders[2].base_lots, 5);
+    assert!(users[1].open_orders[2].is_active);
+    assert_eq!(users[1].open_orders[2].base_lots, 4);
+    assert!(!users[0].open_orders[0].is_active);
+    assert!(!users[1].open_orders[0].is_active);
+}
+
+#[test]
+fn rfq_block_trades_settle_like_book_fills() {
+    let mut request = QuoteRequest {
+        user: Pubkey::new_unique(),
+        nonce: 0,
+        bump: 0,
+        status: RfqStatus::Open,
+        side_is_bid: true,
+        base_lots: 50,
+        expiry_ts: 1_000,
+    };
+    assert!(request.is_open(999));
+    assert!(!request.is_open(1_000));
+
+    let user = UserAccount {
+        open_orders: vec![Order::default(); 2],
+        ..test_user()
+    };
+    let mut block_taker = user.clone();
+    let mut block_maker = UserAccount {
+        owner: Pubkey::new_unique(),
+        ..user.clone()
+    };
+    let mut events = Vec::new();
+    let quote_change = settle_trade(
+        &mut block_taker,
+        &mut block_maker,
+        request.side_is_bid,
+        101,
+        request.base_lots,
+        &mut events,
+    );
+    request.status = RfqStatus::Filled;
+    assert!(!request.is_open(0));
+
+    // The same fill against a resting ask ends in identical balances.
+    let mut book_taker = user.clone();
+    let mut book_maker = UserAccount {
+        owner: block_maker.owner,
+        ..user
+    };
+    book_maker.open_orders[0] = Order {
+        id: 1,
+        price_lots: 101,
+        base_lots: 50,
+        side_is_bid: false,
+        is_active: true,
+        ..Order::default()
+    };
+    let mut book_events = Vec::new();
+    let mut max_quote_change = 0i64;
+    let mut makers = [book_maker];
+    match_orders(
+        &mut book_taker,
+        &mut makers[..],
+        101,
+        50,
+        &mut max_quote_change,
+        true,
+        0,
+        &mut 0,
+        0,
+        &mut book_events,
+    );
+
+    assert_eq!(quote_change, max_quote_change);
+    assert_eq!(block_taker.base_position, book_taker.base_position);
+    assert_eq!(block_taker.quote_position, book_taker.quote_position);
+    assert_eq!(block_maker.base_position, makers[0].base_position);
+    assert_eq!(block_maker.quote_position, makers[0].quote_position);
+    assert!(matches!(
+        events[..],
+        [Event::Trade {
+            price_lots: 101,
+            base_lots: 50,
+            ..
+        }]
+    ));
+}
+
+#[test]
+fn settled_pnl_moves_from_loser_deposits_and_matures() {
+    let user = UserAccount {
+        quote_position: 1_000,
+        net_deposits_lots: 1_000,
+        open_orders: vec![],
+        ..test_user()
+    };
+    // Both deposited 1_000; the long bought 10 lots at 100 from the short.
+    let mut long = UserAccount {
+        base_position: 10,
+        quote_position: 0,
+        ..user.clone()
+    };
+    let mut short = UserAccount {
+        owner: Pubkey::new_unique(),
+        base_position: -10,
+        quote_position: 2_000,
+        ..user
+    };
+    assert_eq!(long.unrealized_pnl(130).unwrap(), 300);
+    assert_eq!(short.unrealized_pnl(130).unwrap(), -300);
+
+    let settled = settle_pnl(&mut short, &mut long, 130, 1_000, 60).unwrap();
+    assert_eq!(settled, 300);
+    assert_eq!(long.unrealized_pnl(130).unwrap(), 0);
+    assert_eq!(short.unrealized_pnl(130).unwrap(), 0);
+    assert_eq!(long.pending_pnl_lots, 300);
+    assert_eq!(short.net_deposits_lots, 700);
+    assert_eq!(
+        settle_pnl(&mut long, &mut short, 130, 1_000, 60).unwrap_err(),
+        EngineError::NoSettleablePnl
+    );
+
+    // A small later settlement barely delays what is already pending.
+    assert_eq!(settle_pnl(&mut long, &mut short, 131, 1_050, 60), Ok(10));
+    assert_eq!(long.pending_pnl_lots, 310);
+    assert_eq!(long.pending_pnl_ts, 1_001);
+
+    // An underwater loser only pays out what it still has deposited.
+    let mut bigger_long = UserAccount {
+        owner: Pubkey::new_unique(),
+        base_position: 20,
+        quote_position: -1_000,
+        ..test_user()
+    };
+    let mut broke_short = short.clone();
+    broke_short.base_position = -20;
+    assert_eq!(
+        settle_pnl(&mut bigger_long, &mut broke_short, 200, 1_050, 60),
+        Ok(690)
+    );
+    assert_eq!(broke_short.net_deposits_lots, 0);
+    assert_eq!(
+        settle_pnl(&mut bigger_long, &mut broke_short, 200, 1_050, 60),
+        Err(EngineError::NoSettleablePnl)
+    );
+
+    // Settled PnL only becomes withdrawable after the maturity delay.
+    long.mature_pnl(1_060, 60);
+    assert_eq!(long.settled_pnl_lots, 0);
+    long.mature_pnl(1_061, 60);
+    assert_eq!(long.settled_pnl_lots, 310);
+    assert_eq!(long.pending_pnl_lots, 0);
+}
+
+#[test]
+fn spot_balances_lock_behind_resting_orders() {
+    let mut user = UserAccount {
+        base_position: 50,
+        quote_position: 10_000,
+        open_orders: vec![Order::default(); 4],
+        ..test_user()
+    };
+    user.open_orders[0] = Order {
+        id: 1,
+        price_lots: 100,
+        base_lots: 20,
+        side_is_bid: true,
+        is_active: true,
+        ..Order::default()
+    };
+    user.open_orders[1] = Order {
+        id: 2,
+        price_lots: 120,
+        base_lots: 5,
+        side_is_bid: false,
+        is_active: true,
+        display_base_lots: 5,
+        hidden_base_lots: 10,
+        ..Order::default()
+    };
+    // Cancelled slots release their reservation.
+    user.open_orders[2] = Order {
+        id: 3,
+        price_lots: 100,
+        base_lots: 30,
+        side_is_bid: true,
+        is_active: false,
+        ..Order::default()
+    };
+
+    user.update_locked_lots(MarketKind::Spot);
+    assert_eq!((user.locked_base_lots, user.locked_quote_lots), (15, 2_000));
+    assert_eq!(user.free_base_lots(), 35);
+    assert_eq!(user.free_quote_lots(), 8_000);
+
+    let mut market = test_market();
+    assert_eq!(
+        market.require_kind(MarketKind::Spot).unwrap_err(),
+        EngineError::InvalidMarketKind
+    );
+    market.kind = MarketKind::Spot;
+    assert!(market.require_kind(MarketKind::Spot).is_ok());
+
+    let mint = Pubkey::new_unique();
+    let owner = Pubkey::new_unique();
+    let mut data = vec![0u8; TOKEN_ACCOUNT_LEN];
+    data[..32].copy_from_slice(mint.as_ref());
+    data[32..64].copy_from_slice(owner.as_ref());
+    data[64..72].copy_from_slice(&1_234u64.to_le_bytes());
+    let token = unpack_token_account(&data).unwrap();
+    assert_eq!(
+        (token.mint, token.owner, token.amount),
+        (mint, owner, 1_234)
+    );
+    assert!(unpack_token_account(&data[..64]).is_err());
+}
+
+#[test]
+fn perp_orders_count_towards_worst_case_exposure() {
+    let mut maker = UserAccount {
+        quote_position: 5_000,
+        net_deposits_lots: 5_000,
+        open_orders: vec![Order::default(); 4],
+        ..test_user()
+    };
+    rest_order(
+        &mut maker,
+        Order {
+            id: 1,
+            price_lots: 100,
+            base_lots: 20,
+            side_is_bid: false,
+            is_active: true,
+            ..Order::default()
+        },
+    )
+    .unwrap();
+    rest_order(
+        &mut maker,
+        Order {
+            id: 2,
+            price_lots: 90,
+            base_lots: 10,
+            side_is_bid: true,
+            is_active: true,
+            ..Order::default()
+        },
+    )
+    .unwrap();
+
+    // Perp orders lock no balances; the margin check covers the short
+    // that all asks filling would open.
+    maker.update_locked_lots(MarketKind::Perp);
+    assert_eq!((maker.locked_base_lots, maker.locked_quote_lots), (0, 0));
+    assert_eq!(maker.max_exposure_lots(), 20);
+    let market = test_market();
+    assert_eq!(market.initial_margin(20, 100), Ok(200));
+    assert_eq!(market.initial_margin(-3, 33), Ok(10));
+
+    let mut taker = UserAccount {
+        owner: Pubkey::new_unique(),
+        ..maker.clone()
+    };
+    taker.open_orders = vec![Order::default(); 4];
+    let mut makers = [maker];
+    let mut max_quote_change = 0i64;
+    let mut events = Vec::new();
+    match_orders(
+        &mut taker,
+        &mut makers[..],
+        100,
+        15,
+        &mut max_quote_change,
+        true,
+        0,
+        &mut 0,
+        0,
+        &mut events,
+    );
+
+    // Filling part of the ask moves it into the position without changing
+    // the worst case, and the short's proceeds add no equity.
+    let [mut maker] = makers;
+    assert_eq!(maker.quote_position, 6_500);
+    assert_eq!(maker.max_exposure_lots(), 20);
+    assert_eq!(maker.equity(100), Ok(5_000));
+    assert_eq!(maker.equity(110), Ok(4_850));
+
+    cancel_orders(&mut maker, None, usize::MAX, &mut events);
+    assert_eq!(maker.max_exposure_lots(), 15);
+}
+
+#[test]
+fn only_the_admin_consumes_events_that_block_closing() {
+    set_clock(0);
+    let mut market = test_market();
+    let [mut market_ai, mut queue_ai, _] = market_accounts(&mut market, 100);
+    let owner = Pubkey::new_unique();
+    let mut first = user_account(
+        &market_ai.key,
+        UserAccount {
+            owner,
+            ..test_user()
+        },
+    );
+    let mut second = user_account(
+        &market_ai.key,
+        UserAccount {
+            owner,
+            account_num: 1,
+            ..test_user()
+        },
+    );
+    let mut owner_ai = TestAccount::signer(owner);
+    let out = Event::Out {
+        owner,
+        account_num: 0,
+        order_id: 7,
+        side_is_bid: true,
+        base_lots: 3,
+    };
+    push_events(&matching_engine::id(), &queue_ai.info(), &[out]).unwrap();
+
+    // Only the account the pending event refers to is held open.
+    let close = EngineInstruction::CloseUserAccount;
+    let err = process(
+        &mut [&mut market_ai, &mut first, &mut owner_ai, &mut queue_ai],
+        &close,
+    )
+    .unwrap_err();
+    assert_eq!(err, EngineError::AccountNotEmpty.into());
+    process(
+        &mut [&mut market_ai, &mut second, &mut owner_ai, &mut queue_ai],
+        &close,
+    )
+    .unwrap();
+
+    // The owner cannot drop the event themselves to get around that.
+    let consume = EngineInstruction::ConsumeEvents { limit: 8 };
+    let err = process(
+        &mut [&mut market_ai, &mut owner_ai, &mut queue_ai],
+        &consume,
+    )
+    .unwrap_err();
+    assert_eq!(err, EngineError::Unauthorized.into());
+
+    let mut admin_ai = TestAccount::signer(market.admin);
+    process(
+        &mut [&mut market_ai, &mut admin_ai, &mut queue_ai],
+        &consume,
+    )
+    .unwrap();
+    let header: EventQueueHeader = queue_ai.load();
+    assert_eq!(header.head, header.tail);
+    process(
+        &mut [&mut market_ai, &mut first, &mut owner_ai, &mut queue_ai],
+        &close,
+    )
+    .unwrap();
+    assert_eq!(owner_ai.lamports, 2_000_000);
+}
+
+#[test]
+fn admin_handoff_needs_the_nominee_to_accept() {
+    set_clock(0);
+    let mut market = test_market();
+    let [mut market_ai, ..] = market_accounts(&mut market, 100);
+    let mut admin_ai = TestAccount::signer(market.admin);
+    let nominee = Pubkey::new_unique();
+    let mut nominee_ai = TestAccount::signer(nominee);
+    let mut stranger_ai = TestAccount::signer(Pubkey::new_unique());
+    let unauthorized = Err(EngineError::Unauthorized.into());
+
+    let propose = |new_admin| EngineInstruction::ProposeAdmin { new_admin };
+    let accept = EngineInstruction::AcceptAdmin;
+    let pause = EngineInstruction::SetMarketStatus {
+        status: MarketStatus::Paused,
+    };
+
+    // Proposing the default key withdraws the nomination.
+    process(&mut [&mut market_ai, &mut admin_ai], &propose(nominee)).unwrap();
+    process(
+        &mut [&mut market_ai, &mut admin_ai],
+        &propose(Pubkey::default()),
+    )
+    .unwrap();
+    assert_eq!(
+        process(&mut [&mut market_ai, &mut nominee_ai], &accept),
+        unauthorized
+    );
+    let mut default_ai = TestAccount::signer(Pubkey::default());
+    assert_eq!(
+        process(&mut [&mut market_ai, &mut default_ai], &accept),
+        unauthorized
+    );
+
+    process(&mut [&mut market_ai, &mut admin_ai], &propose(nominee)).unwrap();
+    assert_eq!(
+        process(&mut [&mut market_ai, &mut stranger_ai], &accept),
+        unauthorized
+    );
+    nominee_ai.is_signer = false;
+    assert_eq!(
+        process(&mut [&mut market_ai, &mut nominee_ai], &accept),
+        unauthorized
+    );
+    nominee_ai.is_signer = true;
+    process(&mut [&mut market_ai, &mut nominee_ai], &accept).unwrap();
+
+    let stored: Market = market_ai.load();
+    assert_eq!(stored.admin, nominee);
+    assert_eq!(stored.pending_admin, Pubkey::default());
+    assert_eq!(
+        process(&mut [&mut market_ai, &mut admin_ai], &pause),
+        unauthorized
+    );
+    process(&mut [&mut market_ai, &mut nominee_ai], &pause).unwrap();
+}
+
+#[test]
+fn reduce_only_market_caps_orders_by_everything_resting() {
+    set_clock(0);
+    let mut market = Market {
+        status: MarketStatus::ReduceOnly,
+        ..test_market()
+    };
+    let [mut market_ai, mut queue_ai, mut oracle_ai] = market_accounts(&mut market, 100);
+    let owner = Pubkey::new_unique();
+    let mut user_ai = user_account(
+        &market_ai.key,
+        UserAccount {
+            owner,
+            base_position: 10,
+            quote_position: -1_000,
+            net_deposits_lots: 1_000,
+            ..test_user()
+        },
+    );
+    let mut owner_ai = TestAccount::signer(owner);
+    let place = |side_is_bid, max_base_lots| EngineInstruction::PlaceOrder {
+        price_lots: 120,
+        max_base_lots,
+        side_is_bid,
+        order_type: OrderType::Limit,
+        expiry_timestamp: None,
+        reduce_only: false,
+        peg: None,
+        display_base_lots: None,
+    };
+    let mut run = |ix: &EngineInstruction| {
+        process(
+            &mut [
+                &mut market_ai,
+                &mut user_ai,
+                &mut owner_ai,
+                &mut queue_ai,
+                &mut oracle_ai,
+            ],
+            ix,
+        )
+    };
+
+    // Two asks that each reduce the long 10 on their own may not rest
+    // together beyond the position.
+    run(&place(false, 6)).unwrap();
+    run(&place(false, 6)).unwrap();
+    let not_reducing = Err(EngineError::OrderNotReducing.into());
+    assert_eq!(run(&place(false, 1)), not_reducing);
+    assert_eq!(run(&place(true, 1)), not_reducing);
+
+    let user: UserAccount = user_ai.load();
+    let asks: Vec<_> = user
+        .open_orders
+        .iter()
+        .filter(|o| o.is_active)
+        .map(|o| (o.base_lots, o.reduce_only))
+        .collect();
+    assert_eq!(asks, [(6, true), (4, true)]);
+}
+
+#[test]
+fn batch_placement_shares_one_load_and_can_replace_the_book() {
+    set_clock(0);
+    let mut market = test_market();
+    let [mut market_ai, mut queue_ai, mut oracle_ai] = market_accounts(&mut market, 100);
+    let owner = Pubkey::new_unique();
+    let mut stale = vec![Order::default(); 8];
+    stale[0] = Order {
+        id: 1,
+        price_lots: 90,
+        base_lots: 4,
+        side_is_bid: true,
+        is_active: true,
+        ..Order::default()
+    };
+    let mut user_ai = user_account(
+        &market_ai.key,
+        UserAccount {
+            owner,
+            quote_position: 10_000,
+            net_deposits_lots: 10_000,
+            open_orders: stale,
+            ..test_user()
+        },
+    );
+    let mut maker_orders = vec![Order::default(); 8];
+    maker_orders[0] = Order {
+        id: 2,
+        price_lots: 100,
+        base_lots: 5,
+        side_is_bid: false,
+        is_active: true,
+        ..Order::default()
+    };
+    let mut maker_ai = user_account(
+        &market_ai.key,
+        UserAccount {
+            base_position: 5,
+            open_orders: maker_orders,
+            ..test_user()
+        },
+    );
+    let mut owner_ai = TestAccount::signer(owner);
+    let bid = |max_base_lots| OrderParams {
+        price_lots: 100,
+        max_base_lots,
+        side_is_bid: true,
+        order_type: OrderType::Limit,
+        expiry_timestamp: None,
+        reduce_only: false,
+        peg: None,
+        display_base_lots: None,
+    };
+    let mut run = |orders: Vec<OrderParams>| {
+        process(
+            &mut [
+                &mut market_ai,
+                &mut user_ai,
+                &mut owner_ai,
+                &mut queue_ai,
+                &mut oracle_ai,
+                &mut maker_ai,
+            ],
+            &EngineInstruction::PlaceOrders {
+                orders,
+                cancel_existing: true,
+            },
+        )
+    };
+
+    assert_eq!(
+        run(vec![bid(1); MAX_BATCH_ORDERS + 1]),
+        Err(EngineError::TooManyOrders.into())
+    );
+
+    // The second order sees the maker as the first one left it.
+    run(vec![bid(3), bid(5)]).unwrap();
+    let user: UserAccount = user_ai.load();
+    let maker: UserAccount = maker_ai.load();
+    assert_eq!(user.base_position, 5);
+    assert_eq!(maker.base_position, 0);
+    let resting: Vec<_> = user
+        .open_orders
+        .iter()
+        .filter(|o| o.is_active)
+        .map(|o| (o.price_lots, o.base_lots))
+        .collect();
+    assert_eq!(resting, [(100, 3)]);
+
+    let header: EventQueueHeader = queue_ai.load();
+    let data = &queue_ai.data[EventQueueHeader::DATA_OFFSET..];
+    let events: Vec<_> = (header.head..header.tail)
+        .map(|seq| read_event(&header, data, seq).unwrap())
+        .collect();
+    assert!(matches!(
+        events[..],
+        [
+            Event::Out {
+                order_id: 1,
+                base_lots: 4,
+                ..
+            },
+            Event::Trade { base_lots: 3, .. },
+            Event::Trade { base_lots: 2, .. },
+        ]
+    ));
+}
+
+#[test]
+fn batch_placement_is_bounded_by_maker_slots_scanned() {
+    set_clock(0);
+    let mut market = test_market();
+    let [mut market_ai, mut queue_ai, mut oracle_ai] = market_accounts(&mut market, 100);
+    let owner = Pubkey::new_unique();
+    let mut user_ai = user_account(
+        &market_ai.key,
+        UserAccount {
+            owner,
+            quote_position: 10_000,
+            net_deposits_lots: 10_000,
+            open_orders: vec![Order::default(); MAX_BATCH_ORDERS],
+            ..test_user()
+        },
+    );
+    let mut owner_ai = TestAccount::signer(owner);
+    let full_maker = || UserAccount {
+        open_orders: vec![Order::default(); UserAccount::MAX_OPEN_ORDERS],
+        ..test_user()
+    };
+    let mut makers = [
+        user_account(&market_ai.key, full_maker()),
+        user_account(&market_ai.key, full_maker()),
+        user_account(&market_ai.key, full_maker()),
+    ];
+    let bid = OrderParams {
+        price_lots: 90,
+        max_base_lots: 1,
+        side_is_bid: true,
+        order_type: OrderType::Limit,
+        expiry_timestamp: None,
+        reduce_only: false,
+        peg: None,
+        display_base_lots: None,
+    };
+    let mut run = |num_makers: usize, num_orders: usize| {
+        let mut accounts = vec![
+            &mut market_ai,
+            &mut user_ai,
+            &mut owner_ai,
+            &mut queue_ai,
+            &mut oracle_ai,
+        ];
+        accounts.extend(makers.iter_mut().take(num_makers));
+        process(
+            &mut accounts,
+            &EngineInstruction::PlaceOrders {
+                orders: vec![bid.clone(); num_orders],
+                cancel_existing: true,
+            },
+        )
+    };
+
+    // A full batch fits against two full makers but not three, where a
+    // smaller batch still does.
+    let slots = 2 * UserAccount::MAX_OPEN_ORDERS;
+    assert_eq!(MAX_BATCH_ORDERS * slots, MAX_BATCH_MAKER_SLOTS);
+    run(2, MAX_BATCH_ORDERS).unwrap();
+    assert_eq!(
+        run(3, MAX_BATCH_ORDERS),
+        Err(EngineError::TooManyOrders.into())
+    );
+    run(
+        3,
+        MAX_BATCH_MAKER_SLOTS / (3 * UserAccount::MAX_OPEN_ORDERS),
+    )
+    .unwrap();
+}
+
+#[test]
+fn keepers_are_only_paid_for_triggers_that_execute() {
+    set_clock(0);
+    let mut market = test_market();
+    let [mut market_ai, mut queue_ai, mut oracle_ai] = market_accounts(&mut market, 94);
+    let mut user_ai = user_account(
+        &market_ai.key,
+        UserAccount {
+            base_position: 10,
+            quote_position: -900,
+            net_deposits_lots: 100,
+            ..test_user()
+        },
+    );
+    let (triggers_key, bump) = find_trigger_orders_address(&matching_engine::id(), &user_ai.key);
+    let stop_loss = TriggerOrder {
+        id: 0,
+        trigger_price: 95,
+        direction: TriggerDirection::Below,
+        params: OrderParams {
+            price_lots: 90,
+            max_base_lots: 5,
+            side_is_bid: false,
+            order_type: OrderType::ImmediateOrCancel,
+            expiry_timestamp: None,
+            reduce_only: true,
+            peg: None,
+            display_base_lots: None,
+        },
+    };
+    let mut triggers_ai = TestAccount::program(
+        triggers_key,
+        &TriggerOrders {
+            user: user_ai.key,
+            bump,
+            next_id: 1,
+            orders: vec![stop_loss],
+        },
+        TriggerOrders::LEN,
+    );
+    let mut keeper_ai = user_account(&market_ai.key, test_user());
+    let mut maker_orders = vec![Order::default(); 8];
+    maker_orders[0] = Order {
+        id: 1,
+        price_lots: 92,
+        base_lots: 5,
+        side_is_bid: true,
+        is_active: true,
+        ..Order::default()
+    };
+    let mut maker_ai = user_account(
+        &market_ai.key,
+        UserAccount {
+            quote_position: 1_000,
+            open_orders: maker_orders,
+            ..test_user()
+        },
+    );
+    let execute = EngineInstruction::ExecuteTriggerOrder { trigger_id: 0 };
+
+    // Without crossing liquidity the IOC fills nothing: the stop-loss stays
+    // armed and the keeper earns nothing.
+    let err = process(
+        &mut [
+            &mut market_ai,
+            &mut user_ai,
+            &mut triggers_ai,
+            &mut keeper_ai,
+            &mut queue_ai,
+            &mut oracle_ai,
+        ],
+        &execute,
+    )
+    .unwrap_err();
+    assert_eq!(err, EngineError::OrderNotExecuted.into());
+    assert_eq!(triggers_ai.load::<TriggerOrders>().orders.len(), 1);
+
+    process(
+        &mut [
+            &mut market_ai,
+            &mut user_ai,
+            &mut triggers_ai,
+            &mut keeper_ai,
+            &mut queue_ai,
+            &mut oracle_ai,
+            &mut maker_ai,
+        ],
+        &execute,
+    )
+    .unwrap();
+    let user: UserAccount = user_ai.load();
+    let keeper: UserAccount = keeper_ai.load();
+    assert!(triggers_ai.load::<TriggerOrders>().orders.is_empty());
+    assert_eq!(user.base_position, 5);
+    assert_eq!(
+        user.quote_position,
+        -900 + 5 * 92 - Market::DEFAULT_KEEPER_FEE_LOTS
+    );
+    assert_eq!(keeper.quote_position, Market::DEFAULT_KEEPER_FEE_LOTS);
+}
+
+#[test]
+fn twap_slices_only_count_when_they_fill() {
+    set_clock(1_000);
+    let mut market = test_market();
+    let [mut market_ai, mut queue_ai, mut oracle_ai] = market_accounts(&mut market, 100);
+    let owner = Pubkey::new_unique();
+    let delegate = Pubkey::new_unique();
+    let mut user_ai = user_account(
+        &market_ai.key,
+        UserAccount {
+            owner,
+            delegate,
+            quote_position: 10_000,
+            net_deposits_lots: 10_000,
+            ..test_user()
+        },
+    );
+    let (twap_key, bump) = find_twap_order_address(&matching_engine::id(), &user_ai.key, 0);
+    let mut twap_ai = TestAccount::program(
+        twap_key,
+        &TwapOrder {
+            user: user_ai.key,
+            nonce: 0,
+            bump,
+            status: TwapStatus::Active,
+            side_is_bid: true,
+            limit_price_lots: 100,
+            total_base_lots: 10,
+            filled_base_lots: 0,
+            num_slices: 2,
+            executed_slices: 0,
+            interval_secs: 60,
+            next_slice_ts: 1_000,
+        },
+        TwapOrder::LEN,
+    );
+    let mut asks = vec![Order::default(); 8];
+    asks[0] = Order {
+        id: 1,
+        price_lots: 99,
+        base_lots: 3,
+        is_active: true,
+        ..Order::default()
+    };
+    let mut maker_ai = user_account(
+        &market_ai.key,
+        UserAccount {
+            quote_position: 10_000,
+            net_deposits_lots: 10_000,
+            open_orders: asks,
+            ..test_user()
+        },
+    );
+    let mut owner_ai = TestAccount::signer(owner);
+
+    // Without liquidity a crank could otherwise burn every due slice.
+    let err = process(
+        &mut [
+            &mut market_ai,
+            &mut user_ai,
+            &mut twap_ai,
+            &mut queue_ai,
+            &mut oracle_ai,
+        ],
+        &EngineInstruction::ExecuteTwapSlice,
+    )
+    .unwrap_err();
+    assert_eq!(err, EngineError::OrderNotExecuted.into());
+
+    // Anyone may run a slice that fills.
+    process(
+        &mut [
+            &mut market_ai,
+            &mut user_ai,
+            &mut twap_ai,
+            &mut queue_ai,
+            &mut oracle_ai,
+            &mut maker_ai,
+        ],
+        &EngineInstruction::ExecuteTwapSlice,
+    )
+    .unwrap();
+    let twap: TwapOrder = twap_ai.load();
+    assert_eq!(
+        (
+            twap.filled_base_lots,
+            twap.executed_slices,
+            twap.next_slice_ts
+        ),
+        (3, 1, 1_060)
+    );
+
+    process(
+        &mut [&mut market_ai, &mut user_ai, &mut owner_ai, &mut twap_ai],
+        &EngineInstruction::CancelTwapOrder,
+    )
+    .unwrap();
+    assert_eq!(twap_ai.load::<TwapOrder>().status, TwapStatus::Cancelled);
+    set_clock(1_060);
+    let err = process(
+        &mut [
+            &mut market_ai,
+            &mut user_ai,
+            &mut twap_ai,
+            &mut queue_ai,
+            &mut oracle_ai,
+        ],
+        &EngineInstruction::ExecuteTwapSlice,
+    )
+    .unwrap_err();
+    assert_eq!(err, EngineError::TwapNotActive.into());
+
+    // The delegate may close it, but the rent goes back to the owner.
+    let mut delegate_ai = TestAccount::signer(delegate);
+    process(
+        &mut [
+            &mut market_ai,
+            &mut user_ai,
+            &mut delegate_ai,
+            &mut twap_ai,
+            &mut owner_ai,
+        ],
+        &EngineInstruction::CloseTwapOrder,
+    )
+    .unwrap();
+    assert_eq!(twap_ai.lamports, 0);
+    assert_eq!(owner_ai.lamports, 1_000_000);
+    assert!(twap_ai.data.iter().all(|b| *b == 0));
+}
+
+#[test]
+fn only_the_admin_clears_batch_auctions() {
+    set_clock(500);
+    let mut market = Market {
+        matching_mode: MatchingMode::BatchAuction,
+        auction_interval_secs: 60,
+        ..test_market()
+    };
+    let [mut market_ai, mut queue_ai, mut oracle_ai] = market_accounts(&mut market, 100);
+    let resting = |price_lots, side_is_bid| {
+        let mut orders = vec![Order::default(); 8];
+        orders[0] = Order {
+            id: price_lots as u128,
+            price_lots,
+            base_lots: 5,
+            side_is_bid,
+            is_active: true,
+            ..Order::default()
+        };
+        orders
+    };
+    let mut buyer_ai = user_account(
+        &market_ai.key,
+        UserAccount {
+            open_orders: resting(101, true),
+            ..test_user()
+        },
+    );
+    let mut seller_ai = user_account(
+        &market_ai.key,
+        UserAccount {
+            open_orders: resting(99, false),
+            ..test_user()
+        },
+    );
+    let mut admin_ai = TestAccount::signer(market.admin);
+    let mut stranger_ai = TestAccount::signer(Pubkey::new_unique());
+
+    // A permissionless cranker could leave out orders and steer the price.
+    let err = process(
+        &mut [
+            &mut market_ai,
+            &mut stranger_ai,
+            &mut queue_ai,
+            &mut oracle_ai,
+            &mut buyer_ai,
+            &mut seller_ai,
+        ],
+        &EngineInstruction::ClearAuction,
+    )
+    .unwrap_err();
+    assert_eq!(err, EngineError::Unauthorized.into());
+
+    process(
+        &mut [
+            &mut market_ai,
+            &mut admin_ai,
+            &mut queue_ai,
+            &mut oracle_ai,
+            &mut buyer_ai,
+            &mut seller_ai,
+        ],
+        &EngineInstruction::ClearAuction,
+    )
+    .unwrap();
+    assert_eq!(buyer_ai.load::<UserAccount>().base_position, 5);
+    assert_eq!(seller_ai.load::<UserAccount>().base_position, -5);
+    assert_eq!(market_ai.load::<Market>().auction_end_ts, 560);
+}
+
+#[test]
+fn pre_open_orders_rest_until_the_opening_auction() {
+    set_clock(0);
+    let mut market = Market {
+        status: MarketStatus::PreOpen,
+        ..test_market()
+    };
+    let [mut market_ai, mut queue_ai, mut oracle_ai] = market_accounts(&mut market, 100);
+    let ask = |id, price_lots| Order {
+        id,
+        price_lots,
+        base_lots: 4,
+        side_is_bid: false,
+        is_active: true,
+        ..Order::default()
+    };
+    let mut asks = vec![Order::default(); 8];
+    asks[0] = ask(1, 98);
+    asks[1] = ask(2, 101);
+    let mut seller_ai = user_account(
+        &market_ai.key,
+        UserAccount {
+            quote_position: 10_000,
+            net_deposits_lots: 10_000,
+            open_orders: asks,
+            ..test_user()
+        },
+    );
+    let owner = Pubkey::new_unique();
+    let mut buyer_ai = user_account(
+        &market_ai.key,
+        UserAccount {
+            owner,
+            quote_position: 10_000,
+            net_deposits_lots: 10_000,
+            ..test_user()
+        },
+    );
+    let mut owner_ai = TestAccount::signer(owner);
+    let mut admin_ai = TestAccount::signer(market.admin);
+
+    // Crossing bids rest instead of matching the seller's asks.
+    for (price_lots, max_base_lots) in [(102, 5), (100, 3)] {
+        process(
+            &mut [
+                &mut market_ai,
+                &mut buyer_ai,
+                &mut owner_ai,
+                &mut queue_ai,
+                &mut oracle_ai,
+                &mut seller_ai,
+            ],
+            &EngineInstruction::PlaceOrder {
+                price_lots,
+                max_base_lots,
+                side_is_bid: true,
+                order_type: OrderType::Limit,
+                expiry_timestamp: None,
+                reduce_only: false,
+                peg: None,
+                display_base_lots: None,
+            },
+        )
+        .unwrap();
+    }
+    assert_eq!(buyer_ai.load::<UserAccount>().base_position, 0);
+
+    // Batch auction cranks cannot uncross the pre-open book early.
+    let mut batch = Market {
+        matching_mode: MatchingMode::BatchAuction,
+        auction_interval_secs: 60,
+        ..market.clone()
+    };
+    let [mut batch_ai, mut batch_queue_ai, mut batch_oracle_ai] = market_accounts(&mut batch, 100);
+    let err = process(
+        &mut [
+            &mut batch_ai,
+            &mut admin_ai,
+            &mut batch_queue_ai,
+            &mut batch_oracle_ai,
+        ],
+        &EngineInstruction::ClearAuction,
+    )
+    .unwrap_err();
+    assert_eq!(err, EngineError::MarketInactive.into());
+
+    // Bids 102x5 and 100x3 against asks 98x4 and 101x4 match 5 lots at
+    // both 101 and 102; 101 is nearer the oracle.
+    process(
+        &mut [
+            &mut market_ai,
+            &mut admin_ai,
+            &mut queue_ai,
+            &mut oracle_ai,
+            &mut buyer_ai,
+            &mut seller_ai,
+        ],
+        &EngineInstruction::OpenMarket,
+    )
+    .unwrap();
+    assert_eq!(market_ai.load::<Market>().status, MarketStatus::Active);
+    let buyer: UserAccount = buyer_ai.load();
+    let seller: UserAccount = seller_ai.load();
+    assert_eq!(
+        (buyer.base_position, buyer.quote_position),
+        (5, 10_000 - 505)
+    );
+    assert_eq!(
+        (seller.base_position, seller.quote_position),
+        (-5, 10_000 + 505)
+    );
+    let resting = |user: &UserAccount| -> Vec<_> {
+        user.open_orders
+            .iter()
+            .filter(|o| o.is_active)
+            .map(|o| (o.price_lots, o.base_lots))
+            .collect()
+    };
+    assert_eq!(resting(&buyer), [(100, 3)]);
+    assert_eq!(resting(&seller), [(101, 3)]);
+
+    // The opening auction runs once.
+    let err = process(
+        &mut [&mut market_ai, &mut admin_ai, &mut queue_ai, &mut oracle_ai],
+        &EngineInstruction::OpenMarket,
+    )
+    .unwrap_err();
+    assert_eq!(err, EngineError::InvalidStatusTransition.into());
+}
+
+#[test]
+fn perp_orders_and_withdrawals_need_initial_margin() {
+    set_clock(0);
+    let mut market = test_market();
+    let [mut market_ai, mut queue_ai, mut oracle_ai] = market_accounts(&mut market, 100);
+    let place = |price_lots, max_base_lots, side_is_bid| EngineInstruction::PlaceOrder {
+        price_lots,
+        max_base_lots,
+        side_is_bid,
+        order_type: OrderType::Limit,
+        expiry_timestamp: None,
+        reduce_only: false,
+        peg: None,
+        display_base_lots: None,
+    };
+    let insufficient = Err(EngineError::InsufficientFunds.into());
+    let seller = Pubkey::new_unique();
+    let mut seller_ai = user_account(
+        &market_ai.key,
+        UserAccount {
+            owner: seller,
+            quote_position: 1_000,
+            net_deposits_lots: 1_000,
+            ..test_user()
+        },
+    );
+    let mut seller_owner_ai = TestAccount::signer(seller);
+    let buyer = Pubkey::new_unique();
+    let mut buyer_ai = user_account(
+        &market_ai.key,
+        UserAccount {
+            owner: buyer,
+            quote_position: 1_000,
+            net_deposits_lots: 1_000,
+            ..test_user()
+        },
+    );
+    let mut buyer_owner_ai = TestAccount::signer(buyer);
+
+    // 1_000 of equity margins 100 lots at 100 under the 10% initial margin,
+    // long or short.
+    process(
+        &mut [
+            &mut market_ai,
+            &mut seller_ai,
+            &mut seller_owner_ai,
+            &mut queue_ai,
+            &mut oracle_ai,
+        ],
+        &place(100, 100, false),
+    )
+    .unwrap();
+    process(
+        &mut [
+            &mut market_ai,
+            &mut buyer_ai,
+            &mut buyer_owner_ai,
+            &mut queue_ai,
+            &mut oracle_ai,
+            &mut seller_ai,
+        ],
+        &place(100, 100, true),
+    )
+    .unwrap();
+    let stored: UserAccount = seller_ai.load();
+    assert_eq!(
+        (stored.base_position, stored.quote_position),
+        (-100, 11_000)
+    );
+
+    // The short's sale proceeds do not margin a bigger short.
+    assert_eq!(
+        process(
+            &mut [
+                &mut market_ai,
+                &mut seller_ai,
+                &mut seller_owner_ai,
+                &mut queue_ai,
+                &mut oracle_ai,
+            ],
+            &place(100, 1, false),
+        ),
+        insufficient
+    );
+    // Bids that can only shrink the short are still allowed, and count
+    // towards the size that may reduce it.
+    process(
+        &mut [
+            &mut market_ai,
+            &mut seller_ai,
+            &mut seller_owner_ai,
+            &mut queue_ai,
+            &mut oracle_ai,
+        ],
+        &place(90, 60, true),
+    )
+    .unwrap();
+    assert_eq!(reducing_capacity(&seller_ai.load(), true), 40);
+
+    // Asks stacked against the long: even once the first 100 lots have
+    // used up the long, another 100 only flip it to an equally margined
+    // short, but a further lot grows the worst case.
+    process(
+        &mut [
+            &mut market_ai,
+            &mut buyer_ai,
+            &mut buyer_owner_ai,
+            &mut queue_ai,
+            &mut oracle_ai,
+        ],
+        &place(150, 100, false),
+    )
+    .unwrap();
+    process(
+        &mut [
+            &mut market_ai,
+            &mut buyer_ai,
+            &mut buyer_owner_ai,
+            &mut queue_ai,
+            &mut oracle_ai,
+        ],
+        &place(150, 100, false),
+    )
+    .unwrap();
+    assert_eq!(buyer_ai.load::<UserAccount>().max_exposure_lots(), 100);
+    assert_eq!(
+        process(
+            &mut [
+                &mut market_ai,
+                &mut buyer_ai,
+                &mut buyer_owner_ai,
+                &mut queue_ai,
+                &mut oracle_ai,
+            ],
+            &place(150, 1, false),
+        ),
+        insufficient
+    );
+
+    // Withdrawals must leave the account initially margined.
+    let withdraw = |lots: u64| EngineInstruction::Withdraw {
+        amount: lots * market.quote_lot_size,
+    };
+    assert_eq!(
+        process(
+            &mut [
+                &mut market_ai,
+                &mut seller_ai,
+                &mut seller_owner_ai,
+                &mut oracle_ai,
+            ],
+            &withdraw(1),
+        ),
+        Err(EngineError::WithdrawLimitExceeded.into())
+    );
+    // Once the price falls the short's gain margins it, so the deposit can
+    // come out.
+    oracle_ai = TestAccount::program(
+        market.oracle,
+        &OraclePrice {
+            price: 90,
+            confidence: 0,
+            last_updated_slot: 0,
+        },
+        64,
+    );
+    process(
+        &mut [
+            &mut market_ai,
+            &mut seller_ai,
+            &mut seller_owner_ai,
+            &mut oracle_ai,
+        ],
+        &withdraw(1_000),
+    )
+    .unwrap();
+    let stored: UserAccount = seller_ai.load();
+    assert_eq!(stored.equity(90), Ok(1_000));
+    assert_eq!(stored.net_deposits_lots, 0);
+}
+
+#[test]
+fn accepted_quotes_check_both_sides_and_close_when_done() {
+    set_clock(0);
+    let mut market = Market {
+        status: MarketStatus::ReduceOnly,
+        ..test_market()
+    };
+    let [mut market_ai, mut queue_ai, mut oracle_ai] = market_accounts(&mut market, 100);
+    // The taker buys back a short; the maker would open one.
+    let owner = Pubkey::new_unique();
+    let delegate = Pubkey::new_unique();
+    let mut taker_ai = user_account(
+        &market_ai.key,
+        UserAccount {
+            owner,
+            delegate,
+            base_position: -10,
+            quote_position: 2_000,
+            net_deposits_lots: 1_000,
+            ..test_user()
+        },
+    );
+    let mut owner_ai = TestAccount::signer(owner);
+    let maker_user = UserAccount {
+        delegate,
+        quote_position: 50,
+        net_deposits_lots: 50,
+        ..test_user()
+    };
+    let mut maker_ai = user_account(&market_ai.key, maker_user.clone());
+    let mut maker_owner_ai = TestAccount::signer(maker_user.owner);
+
+    let (request_key, bump) = find_quote_request_address(&matching_engine::id(), &taker_ai.key, 0);
+    let request = QuoteRequest {
+        user: taker_ai.key,
+        nonce: 0,
+        bump,
+        status: RfqStatus::Open,
+        side_is_bid: true,
+        base_lots: 10,
+        expiry_ts: 1_000,
+    };
+    let mut request_ai = TestAccount::program(request_key, &request, QuoteRequest::LEN);
+    let (quote_key, bump) = find_quote_address(&matching_engine::id(), &request_key, &maker_ai.key);
+    let quote = Quote {
+        request: request_key,
+        maker: maker_ai.key,
+        bump,
+        price_lots: 101,
+        expiry_ts: 1_000,
+        side_is_bid: true,
+        base_lots: 10,
+    };
+    // A request recreated at the same address with new terms cannot fill
+    // quotes priced for the old ones.
+    assert!(quote.matches(&request));
+    assert!(!quote.matches(&QuoteRequest {
+        base_lots: 11,
+        ..request.clone()
+    }));
+    let mut quote_ai = TestAccount::program(quote_key, &quote, Quote::LEN);
+    let mut accept = |market_ai: &mut TestAccount, maker_ai: &mut TestAccount| {
+        process(
+            &mut [
+                market_ai,
+                &mut taker_ai,
+                &mut owner_ai,
+                &mut request_ai,
+                &mut quote_ai,
+                maker_ai,
+                &mut queue_ai,
+                &mut oracle_ai,
+            ],
+            &EngineInstruction::AcceptQuote,
+        )
+    };
+
+    // A reduce-only market rejects the fill because the maker's side is
+    // not reducing, even though the taker's is.
+    assert_eq!(
+        accept(&mut market_ai, &mut maker_ai),
+        Err(EngineError::MarketInactive.into())
+    );
+    market.status = MarketStatus::Active;
+    market_ai = TestAccount::program(market_ai.key, &market, 1_024);
+
+    // The maker's 50 lots of equity do not margin a 10 lot short at 100.
+    assert_eq!(
+        accept(&mut market_ai, &mut maker_ai),
+        Err(EngineError::InsufficientFunds.into())
+    );
+    maker_ai = user_account(
+        &market_ai.key,
+        UserAccount {
+            quote_position: 1_000,
+            net_deposits_lots: 1_000,
+            ..maker_user
+        },
+    );
+    accept(&mut market_ai, &mut maker_ai).unwrap();
+    let taker: UserAccount = taker_ai.load();
+    let maker: UserAccount = maker_ai.load();
+    assert_eq!((taker.base_position, taker.quote_position), (0, 990));
+    assert_eq!((maker.base_position, maker.quote_position), (-10, 2_010));
+    assert_eq!(request_ai.load::<QuoteRequest>().status, RfqStatus::Filled);
+
+    // Both sides close their accounts; the rent goes to the owners even
+    // when the delegate signs.
+    let mut delegate_ai = TestAccount::signer(delegate);
+    process(
+        &mut [
+            &mut market_ai,
+            &mut taker_ai,
+            &mut delegate_ai,
+            &mut request_ai,
+            &mut owner_ai,
+        ],
+        &EngineInstruction::CancelQuoteRequest,
+    )
+    .unwrap();
+    assert_eq!((owner_ai.lamports, request_ai.lamports), (1_000_000, 0));
+    let mut stranger_ai = TestAccount::signer(Pubkey::new_unique());
+    assert_eq!(
+        process(
+            &mut [
+                &mut market_ai,
+                &mut maker_ai,
+                &mut stranger_ai,
+                &mut quote_ai,
+                &mut maker_owner_ai,
+            ],
+            &EngineInstruction::CancelQuote,
+        ),
+        Err(EngineError::Unauthorized.into())
+    );
+    assert_eq!(
+        process(
+            &mut [
+                &mut market_ai,
+                &mut maker_ai,
+                &mut delegate_ai,
+                &mut quote_ai,
+                &mut stranger_ai,
+            ],
+            &EngineInstruction::CancelQuote,
+        ),
+        Err(EngineError::InvalidAccountAddress.into())
+    );
+    process(
+        &mut [
+            &mut market_ai,
+            &mut maker_ai,
+            &mut delegate_ai,
+            &mut quote_ai,
+            &mut maker_owner_ai,
+        ],
+        &EngineInstruction::CancelQuote,
+    )
+    .unwrap();
+    assert_eq!((maker_owner_ai.lamports, quote_ai.lamports), (1_000_000, 0));
+    assert_eq!(delegate_ai.lamports, 0);
+}
+
+#[test]
+fn a_maker_passed_twice_is_rejected() {
+    set_clock(0);
+    let mut market = test_market();
+    let [mut market_ai, mut queue_ai, mut oracle_ai] = market_accounts(&mut market, 100);
+    let mut asks = vec![Order::default(); 8];
+    asks[0] = Order {
+        id: 1,
+        price_lots: 100,
+        base_lots: 10,
+        is_active: true,
+        ..Order::default()
+    };
+    let mut maker_ai = user_account(
+        &market_ai.key,
+        UserAccount {
+            quote_position: 10_000,
+            net_deposits_lots: 10_000,
+            open_orders: asks,
+            ..test_user()
+        },
+    );
+    let mut twin_ai = TestAccount {
+        data: maker_ai.data.clone(),
+        ..maker_ai
+    };
+    let owner = Pubkey::new_unique();
+    let mut taker_ai = user_account(
+        &market_ai.key,
+        UserAccount {
+            owner,
+            quote_position: 10_000,
+            net_deposits_lots: 10_000,
+            ..test_user()
+        },
+    );
+    let mut owner_ai = TestAccount::signer(owner);
+    let bid = EngineInstruction::PlaceOrder {
+        price_lots: 100,
+        max_base_lots: 20,
+        side_is_bid: true,
+        order_type: OrderType::ImmediateOrCancel,
+        expiry_timestamp: None,
+        reduce_only: false,
+        peg: None,
+        display_base_lots: None,
+    };
+
+    assert_eq!(
+        process(
+            &mut [
+                &mut market_ai,
+                &mut taker_ai,
+                &mut owner_ai,
+                &mut queue_ai,
+                &mut oracle_ai,
+                &mut maker_ai,
+                &mut twin_ai,
+            ],
+            &bid,
+        ),
+        Err(EngineError::InvalidAccountData.into())
+    );
+    process(
+        &mut [
+            &mut market_ai,
+            &mut taker_ai,
+            &mut owner_ai,
+            &mut queue_ai,
+            &mut oracle_ai,
+            &mut maker_ai,
+        ],
+        &bid,
+    )
+    .unwrap();
+    assert_eq!(taker_ai.load::<UserAccount>().base_position, 10);
+    assert_eq!(maker_ai.load::<UserAccount>().base_position, -10);
+}
+
+#[test]
+fn replacing_keeps_the_id_only_when_shrinking_in_place() {
+    set_clock(0);
+    let mut market = Market {
+        seq_num: 10,
+        ..test_market()
+    };
+    let [mut market_ai, mut queue_ai, mut oracle_ai] = market_accounts(&mut market, 100);
+    let mut orders = vec![Order::default(); 8];
+    orders[0] = Order {
+        id: 1,
+        price_lots: 90,
+        base_lots: 10,
+        side_is_bid: true,
+        is_active: true,
+        ..Order::default()
+    };
+    orders[1] = Order {
+        id: 2,
+        price_lots: 95,
+        base_lots: 10,
+        side_is_bid: true,
+        is_active: true,
+        is_pegged: true,
+        peg_offset_lots: -5,
+        ..Order::default()
+    };
+    let owner = Pubkey::new_unique();
+    let mut user_ai = user_account(
+        &market_ai.key,
+        UserAccount {
+            owner,
+            quote_position: 1_000,
+            net_deposits_lots: 1_000,
+            open_orders: orders,
+            ..test_user()
+        },
+    );
+    let mut owner_ai = TestAccount::signer(owner);
+    let resting = |user_ai: &TestAccount| -> Vec<_> {
+        user_ai
+            .load::<UserAccount>()
+            .open_orders
+            .iter()
+            .filter(|o| o.is_active)
+            .map(|o| (o.id, o.price_lots, o.total_base_lots(), o.is_pegged))
+            .collect()
+    };
+    let mut replace = |order_id, new_price_lots, new_base_lots| {
+        process(
+            &mut [
+                &mut market_ai,
+                &mut user_ai,
+                &mut owner_ai,
+                &mut queue_ai,
+                &mut oracle_ai,
+            ],
+            &EngineInstruction::ReplaceOrder {
+                order_id,
+                new_price_lots,
+                new_base_lots,
+            },
+        )
+        .map(|()| resting(&user_ai))
+    };
+
+    // Shrinking at the same price keeps the id.
+    assert_eq!(
+        replace(1, 90, 6),
+        Ok(vec![(1, 90, 6, false), (2, 95, 10, true)])
+    );
+    // A new price, or more size, cancels and places a new order.
+    assert_eq!(
+        replace(1, 91, 6),
+        Ok(vec![(10, 91, 6, false), (2, 95, 10, true)])
+    );
+    assert_eq!(
+        replace(10, 91, 8),
+        Ok(vec![(11, 91, 8, false), (2, 95, 10, true)])
+    );
+    // Pegged orders ignore the price: shrinking stays in place and growing
+    // re-places the order on the same peg.
+    assert_eq!(
+        replace(2, 1, 7),
+        Ok(vec![(11, 91, 8, false), (2, 95, 7, true)])
+    );
+    assert_eq!(
+        replace(2, 1, 9),
+        Ok(vec![(11, 91, 8, false), (12, 95, 9, true)])
+    );
+    // A re-place that fails, here for want of margin, leaves the original.
+    assert_eq!(
+        replace(11, 91, 1_000),
+        Err(EngineError::InsufficientFunds.into())
+    );
+    assert_eq!(
+        replace(11, 91, 8),
+        Ok(vec![(11, 91, 8, false), (12, 95, 9, true)])
+    );
+}
+
+#[test]
+fn spot_keeper_fees_come_from_unreserved_quote() {
+    set_clock(0);
+    let mut market = Market {
+        kind: MarketKind::Spot,
+        ..test_market()
+    };
+    let [mut market_ai, mut queue_ai, mut oracle_ai] = market_accounts(&mut market, 94);
+    let user = UserAccount {
+        quote_position: 100,
+        ..test_user()
+    };
+    let (triggers_key, bump) = find_trigger_orders_address(
+        &matching_engine::id(),
+        &user_account(&market_ai.key, user.clone()).key,
+    );
+    let triggers = TriggerOrders {
+        user: Pubkey::default(),
+        bump,
+        next_id: 1,
+        orders: vec![TriggerOrder {
+            id: 0,
+            trigger_price: 95,
+            direction: TriggerDirection::Below,
+            params: OrderParams {
+                price_lots: 10,
+                max_base_lots: 10,
+                side_is_bid: true,
+                order_type: OrderType::Limit,
+                expiry_timestamp: None,
+                reduce_only: false,
+                peg: None,
+                display_base_lots: None,
+            },
+        }],
+    };
+    let mut keeper_ai = user_account(&market_ai.key, test_user());
+    let mut execute = |quote_position| {
+        let mut user_ai = user_account(
+            &market_ai.key,
+            UserAccount {
+                quote_position,
+                ..user.clone()
+            },
+        );
+        let mut triggers_ai = TestAccount::program(
+            triggers_key,
+            &TriggerOrders {
+                user: user_ai.key,
+                ..triggers.clone()
+            },
+            TriggerOrders::LEN,
+        );
+        process(
+            &mut [
+                &mut market_ai,
+                &mut user_ai,
+                &mut triggers_ai,
+                &mut keeper_ai,
+                &mut queue_ai,
+                &mut oracle_ai,
+            ],
+            &EngineInstruction::ExecuteTriggerOrder { trigger_id: 0 },
+        )
+        .map(|()| user_ai.load::<UserAccount>())
+    };
+
+    // The resting bid reserves all 100 quote lots, leaving none for the fee.
+    assert_eq!(
+        execute(100).unwrap_err(),
+        EngineError::InsufficientFunds.into()
+    );
+    let user = execute(100 + Market::DEFAULT_KEEPER_FEE_LOTS).unwrap();
+    assert_eq!((user.quote_position, user.locked_quote_lots), (100, 100));
+    assert_eq!(user.free_quote_lots(), 0);
+}
+
+#[test]
+fn prices_come_only_from_the_market_oracle() {
+    set_clock(0);
+    let mut market = test_market();
+    let [mut market_ai, mut queue_ai, mut oracle_ai] = market_accounts(&mut market, 100);
+    let mut liqor_ai = user_account(&market_ai.key, test_user());
+    let mut liqee_ai = user_account(&market_ai.key, test_user());
+    let mut fake_ai = TestAccount::program(
+        Pubkey::new_unique(),
+        &OraclePrice {
+            price: 1,
+            confidence: 0,
+            last_updated_slot: 0,
+        },
+        64,
+    );
+    assert_eq!(
+        process(
+            &mut [&mut market_ai, &mut liqor_ai, &mut liqee_ai, &mut fake_ai],
+            &EngineInstruction::Liquidate { max_liq_amount: 1 },
+        ),
+        Err(EngineError::InvalidAccountAddress.into())
+    );
+
+    // The oracle's data is only trusted while the program owns it.
+    oracle_ai.owner = Pubkey::new_unique();
+    let owner = Pubkey::new_unique();
+    let mut user_ai = user_account(
+        &market_ai.key,
+        UserAccount {
+            owner,
+            ..test_user()
+        },
+    );
+    let mut owner_ai = TestAccount::signer(owner);
+    assert_eq!(
+        process(
+            &mut [
+                &mut market_ai,
+                &mut user_ai,
+                &mut owner_ai,
+                &mut queue_ai,
+                &mut oracle_ai,
+            ],
+            &EngineInstruction::PlaceOrder {
+                price_lots: 100,
+                max_base_lots: 1,
+                side_is_bid: true,
+                order_type: OrderType::Limit,
+                expiry_timestamp: None,
+                reduce_only: false,
+                peg: None,
+                display_base_lots: None,
+            },
+        ),
+        Err(EngineError::InvalidOwner.into())
+    );
+}